@@ -0,0 +1,19 @@
+// Cargo features can't change codegen flags, so make sure heap-debug builds
+// come with the frame pointers its caller tracking walks.
+
+use std::env;
+
+fn main() {
+  println!("cargo:rerun-if-env-changed=RUSTFLAGS");
+  println!("cargo:rerun-if-env-changed=CARGO_ENCODED_RUSTFLAGS");
+
+  if env::var_os("CARGO_FEATURE_HEAP_DEBUG").is_none() {
+    return;
+  }
+  let rustflags = env::var("CARGO_ENCODED_RUSTFLAGS")
+    .or_else(|_| env::var("RUSTFLAGS"))
+    .unwrap_or_default();
+  if !rustflags.contains("force-frame-pointers=yes") {
+    panic!("the heap-debug feature needs `-C force-frame-pointers=yes` in RUSTFLAGS, build it with `make heap-debug`");
+  }
+}
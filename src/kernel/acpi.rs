@@ -0,0 +1,309 @@
+// ACPI table discovery
+//
+// The RSDP is searched for in the BIOS areas, and the RSDT/XSDT it points to
+// is walked by the acpi crate. Everything the rest of the kernel needs is
+// copied out at boot, since the tables themselves usually live in ACPI
+// reclaimable memory that is handed to the frame allocator afterwards.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{mem, ptr::NonNull, slice};
+use aml::{AmlContext, AmlName, AmlValue, DebugVerbosity};
+use ::acpi::{AcpiHandler, AcpiTables, HpetInfo, InterruptModel, PciConfigRegions, PhysicalMapping, PlatformInfo, fadt::Fadt, platform::{ProcessorInfo, address::GenericAddress}, sdt::{SdtHeader, Signature}};
+use conquer_once::spin::OnceCell;
+use x86_64::{PhysAddr, instructions::port::Port};
+
+use crate::{kernel::{memory, pci}, kprintln};
+
+/// Gives the acpi crate access to the tables through the physical memory map.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelAcpiHandler;
+
+impl AcpiHandler for KernelAcpiHandler {
+  unsafe fn map_physical_region<T>(&self, physical_address: usize, size: usize) -> PhysicalMapping<Self, T> {
+    let virt = memory::phys_to_virt(PhysAddr::new(physical_address as u64));
+    PhysicalMapping::new(
+      physical_address,
+      NonNull::new_unchecked(virt.as_mut_ptr()),
+      size,
+      size,
+      *self,
+    )
+  }
+
+  // Everything is mapped already, nothing to undo
+  fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {}
+}
+
+/// A table listed by the RSDT/XSDT, or the DSDT.
+#[derive(Debug, Clone, Copy)]
+pub struct TableInfo {
+  pub signature: Signature,
+  pub address: PhysAddr,
+  pub length: u32,
+  pub revision: u8,
+  pub oem_id: [u8; 6],
+}
+
+impl TableInfo {
+  pub fn oem_id(&self) -> &str {
+    core::str::from_utf8(&self.oem_id).unwrap_or("??????")
+  }
+}
+
+/// The parts of the FADT the kernel cares about.
+#[derive(Debug, Clone, Copy)]
+pub struct FadtInfo {
+  pub sci_interrupt: u16,
+  pub smi_cmd_port: u32,
+  pub acpi_enable: u8,
+  pub acpi_disable: u8,
+  pub pm1a_control_block: Option<GenericAddress>,
+  pub pm1b_control_block: Option<GenericAddress>,
+  pub pm_timer_block: Option<GenericAddress>,
+  /// Only set when the FADT says the reset register is supported.
+  pub reset_register: Option<GenericAddress>,
+  pub reset_value: u8,
+  /// CMOS index of the RTC century register, 0 if there is none.
+  pub century: u8,
+  pub has_8042: bool,
+  pub hardware_reduced: bool,
+}
+
+impl FadtInfo {
+  fn new(fadt: &Fadt) -> Self {
+    let flags = { fadt.flags };
+    let boot_arch = { fadt.iapc_boot_arch };
+    FadtInfo {
+      sci_interrupt: { fadt.sci_interrupt },
+      smi_cmd_port: { fadt.smi_cmd_port },
+      acpi_enable: { fadt.acpi_enable },
+      acpi_disable: { fadt.acpi_disable },
+      pm1a_control_block: fadt.pm1a_control_block().ok(),
+      pm1b_control_block: fadt.pm1b_control_block().ok().flatten(),
+      pm_timer_block: fadt.pm_timer_block().ok().flatten(),
+      reset_register: if flags.supports_system_reset_via_fadt() { fadt.reset_register().ok() } else { None },
+      reset_value: { fadt.reset_value },
+      century: { fadt.century },
+      has_8042: boot_arch.motherboard_implements_8042(),
+      hardware_reduced: flags.system_is_hw_reduced_acpi(),
+    }
+  }
+}
+
+/// Lets the AML interpreter reach memory, I/O ports and PCI configuration space.
+struct AmlHandler;
+
+impl AmlHandler {
+  fn ptr<T>(address: usize) -> *mut T {
+    memory::phys_to_virt(PhysAddr::new(address as u64)).as_mut_ptr()
+  }
+
+  /// Reads the dword holding `offset` and extracts `size` bytes from it.
+  fn read_pci(bus: u8, device: u8, function: u8, offset: u16, size: u16) -> u32 {
+    // Only the legacy 256 byte space is reachable through the I/O ports
+    if offset >= 256 {
+      return !0;
+    }
+    let dword = pci::read_config(bus, device, function, offset as u8);
+    let shift = (offset % 4) * 8;
+    let mask = if size == 4 { !0 } else { (1 << (size * 8)) - 1 };
+    (dword >> shift) & mask
+  }
+
+  fn write_pci(bus: u8, device: u8, function: u8, offset: u16, size: u16, value: u32) {
+    if offset >= 256 {
+      return;
+    }
+    let shift = (offset % 4) * 8;
+    let mask: u32 = if size == 4 { !0 } else { ((1 << (size * 8)) - 1) << shift };
+    let dword = pci::read_config(bus, device, function, offset as u8);
+    pci::write_config(bus, device, function, offset as u8, (dword & !mask) | ((value << shift) & mask));
+  }
+}
+
+impl aml::Handler for AmlHandler {
+  fn read_u8(&self, address: usize) -> u8 { unsafe { Self::ptr::<u8>(address).read_volatile() } }
+  fn read_u16(&self, address: usize) -> u16 { unsafe { Self::ptr::<u16>(address).read_volatile() } }
+  fn read_u32(&self, address: usize) -> u32 { unsafe { Self::ptr::<u32>(address).read_volatile() } }
+  fn read_u64(&self, address: usize) -> u64 { unsafe { Self::ptr::<u64>(address).read_volatile() } }
+
+  fn write_u8(&mut self, address: usize, value: u8) { unsafe { Self::ptr::<u8>(address).write_volatile(value) } }
+  fn write_u16(&mut self, address: usize, value: u16) { unsafe { Self::ptr::<u16>(address).write_volatile(value) } }
+  fn write_u32(&mut self, address: usize, value: u32) { unsafe { Self::ptr::<u32>(address).write_volatile(value) } }
+  fn write_u64(&mut self, address: usize, value: u64) { unsafe { Self::ptr::<u64>(address).write_volatile(value) } }
+
+  fn read_io_u8(&self, port: u16) -> u8 { unsafe { Port::new(port).read() } }
+  fn read_io_u16(&self, port: u16) -> u16 { unsafe { Port::new(port).read() } }
+  fn read_io_u32(&self, port: u16) -> u32 { unsafe { Port::new(port).read() } }
+
+  fn write_io_u8(&self, port: u16, value: u8) { unsafe { Port::new(port).write(value) } }
+  fn write_io_u16(&self, port: u16, value: u16) { unsafe { Port::new(port).write(value) } }
+  fn write_io_u32(&self, port: u16, value: u32) { unsafe { Port::new(port).write(value) } }
+
+  // Only segment 0 is reachable through the legacy configuration mechanism
+  fn read_pci_u8(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+    Self::read_pci(bus, device, function, offset, 1) as u8
+  }
+  fn read_pci_u16(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+    Self::read_pci(bus, device, function, offset, 2) as u16
+  }
+  fn read_pci_u32(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+    Self::read_pci(bus, device, function, offset, 4)
+  }
+
+  fn write_pci_u8(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u8) {
+    Self::write_pci(bus, device, function, offset, 1, value as u32)
+  }
+  fn write_pci_u16(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u16) {
+    Self::write_pci(bus, device, function, offset, 2, value as u32)
+  }
+  fn write_pci_u32(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+    Self::write_pci(bus, device, function, offset, 4, value)
+  }
+}
+
+/// SLP_TYP values to write to the PM1a and PM1b control blocks to enter a
+/// sleep state.
+#[derive(Debug, Clone, Copy)]
+pub struct SleepType {
+  pub pm1a: u16,
+  pub pm1b: u16,
+}
+
+/// Interprets the DSDT and evaluates the `\_S5` (soft off) package.
+fn evaluate_s5(dsdt: &::acpi::AmlTable) -> Option<SleepType> {
+  let stream = unsafe {
+    slice::from_raw_parts(
+      memory::phys_to_virt(PhysAddr::new(dsdt.address as u64)).as_ptr::<u8>(),
+      dsdt.length as usize,
+    )
+  };
+
+  let mut context = AmlContext::new(Box::new(AmlHandler), DebugVerbosity::None);
+  // Firmware often uses opcodes the interpreter doesn't know yet, but \_S5
+  // is usually declared early enough to be parsed anyway
+  if let Err(err) = context.parse_table(stream) {
+    kprintln!("[ ACPI ] Failed to fully parse the DSDT: {:?}", err);
+  }
+
+  let path = AmlName::from_str("\\_S5").ok()?;
+  let elements = match context.namespace.get_by_path(&path) {
+    Ok(AmlValue::Package(elements)) => elements,
+    _ => return None,
+  };
+  let pm1a = elements.get(0)?.as_integer(&context).ok()?;
+  let pm1b = elements.get(1).and_then(|value| value.as_integer(&context).ok()).unwrap_or(0);
+  Some(SleepType { pm1a: pm1a as u16, pm1b: pm1b as u16 })
+}
+
+struct AcpiInfo {
+  revision: u8,
+  tables: Vec<TableInfo>,
+  platform: Option<PlatformInfo>,
+  fadt: Option<FadtInfo>,
+  hpet: Option<HpetInfo>,
+  pci_config_regions: Option<PciConfigRegions>,
+  s5_sleep_type: Option<SleepType>,
+}
+
+static ACPI_INFO: OnceCell<AcpiInfo> = OnceCell::uninit();
+
+/// Reads the header of the table at `address`.
+fn table_info(address: usize) -> TableInfo {
+  let header = unsafe { &*memory::phys_to_virt(PhysAddr::new(address as u64)).as_ptr::<SdtHeader>() };
+  TableInfo {
+    signature: header.signature,
+    address: PhysAddr::new(address as u64),
+    length: { header.length },
+    revision: header.revision,
+    oem_id: header.oem_id,
+  }
+}
+
+/// Parses the ACPI tables the BIOS left in memory.
+///
+/// Everything needed later on is copied out, so this must run before the
+/// ACPI reclaimable memory is handed to the frame allocator.
+pub fn init() {
+  kprintln!("[ ACPI ] Looking for ACPI tables...");
+
+  let tables = match unsafe { AcpiTables::search_for_rsdp_bios(KernelAcpiHandler) } {
+    Ok(tables) => tables,
+    Err(err) => {
+      kprintln!("[ ACPI ] No usable ACPI tables found: {:?}", err);
+      return;
+    }
+  };
+
+  let mut table_list: Vec<TableInfo> = tables.sdts.values()
+    .map(|sdt| table_info(sdt.physical_address))
+    .collect();
+  if let Some(dsdt) = &tables.dsdt {
+    // The address the crate keeps points past the header
+    table_list.push(table_info(dsdt.address - mem::size_of::<SdtHeader>()));
+  }
+
+  let platform = tables.platform_info()
+    .map_err(|err| kprintln!("[ ACPI ] Failed to read platform information: {:?}", err))
+    .ok();
+  let fadt = match unsafe { tables.get_sdt::<Fadt>(Signature::FADT) } {
+    Ok(Some(fadt)) => Some(FadtInfo::new(&fadt)),
+    _ => None,
+  };
+
+  ACPI_INFO.init_once(|| AcpiInfo {
+    revision: tables.revision,
+    tables: table_list,
+    platform,
+    fadt,
+    hpet: HpetInfo::new(&tables).ok(),
+    pci_config_regions: PciConfigRegions::new(&tables).ok(),
+    s5_sleep_type: tables.dsdt.as_ref().and_then(evaluate_s5),
+  });
+
+  kprintln!("[ ACPI ] Found {} tables (revision {}).", tables.sdts.len(), tables.revision);
+}
+
+fn info() -> Option<&'static AcpiInfo> {
+  ACPI_INFO.get()
+}
+
+/// ACPI revision, as inferred from the RSDT/XSDT.
+pub fn revision() -> Option<u8> {
+  info().map(|info| info.revision)
+}
+
+/// Every table found at boot, the DSDT included.
+pub fn tables() -> &'static [TableInfo] {
+  info().map(|info| &info.tables[..]).unwrap_or(&[])
+}
+
+/// Power management registers and flags from the FADT.
+pub fn fadt() -> Option<&'static FadtInfo> {
+  info().and_then(|info| info.fadt.as_ref())
+}
+
+/// How interrupts are delivered, as described by the MADT.
+pub fn interrupt_model() -> Option<&'static InterruptModel> {
+  info().and_then(|info| info.platform.as_ref()).map(|platform| &platform.interrupt_model)
+}
+
+/// The processors listed in the MADT.
+pub fn processor_info() -> Option<&'static ProcessorInfo> {
+  info().and_then(|info| info.platform.as_ref()).and_then(|platform| platform.processor_info.as_ref())
+}
+
+/// Where the HPET registers live, if the machine has one.
+pub fn hpet() -> Option<&'static HpetInfo> {
+  info().and_then(|info| info.hpet.as_ref())
+}
+
+/// SLP_TYP values for the soft off state, from the DSDT.
+pub fn s5_sleep_type() -> Option<SleepType> {
+  info().and_then(|info| info.s5_sleep_type)
+}
+
+/// The PCIe enhanced configuration space regions from the MCFG.
+pub fn pci_config_regions() -> Option<&'static PciConfigRegions> {
+  info().and_then(|info| info.pci_config_regions.as_ref())
+}
@@ -18,9 +18,80 @@ pub async fn run_command(command: &str, args: Vec<String>) {
     "sleep" => {
       let seconds_string = args_iter.next()
         .expect("Sleep command requires one parameter");
-      let ms = seconds_string.parse::<f64>()
+      let seconds = seconds_string.parse::<f64>()
         .expect("Invalid argument seconds");
-      time::sleep(ms);
+      time::sleep_for((seconds * 1_000_000_000.0) as u64).await;
+    },
+    "meminfo" => {
+      use crate::kernel::memory::{self, allocator};
+      let heap = allocator::heap_stats();
+      let frames = memory::frame_stats();
+      kprintln!("Heap:   {} KiB used, {} KiB free, {} KiB mapped (limit {} KiB)",
+        heap.used / 1024, heap.free / 1024, heap.size / 1024, heap.limit / 1024);
+      kprintln!("Frames: {} used, {} free, {} total ({} KiB free)",
+        frames.used, frames.free, frames.total, frames.free * 4);
+      kprintln!("Largest free block: {} KiB", frames.largest_free_block * 4);
+    },
+    "memmap" => {
+      use crate::kernel::memory;
+      for region in memory::memory_map().iter() {
+        let start = region.range.start_addr();
+        let end = region.range.end_addr();
+        kprintln!("{:#012x}-{:#012x} {:>10} KiB {:?}", start, end, (end - start) / 1024, region.region_type);
+      }
+    },
+    "slabinfo" => {
+      use crate::kernel::memory::slab;
+      kprintln!("{:<16} {:>8} {:>8} {:>8} {:>8} {:>6}", "name", "size", "per-slab", "in-use", "free", "slabs");
+      for cache in slab::caches() {
+        kprintln!("{:<16} {:>8} {:>8} {:>8} {:>8} {:>6}", cache.name, cache.object_size,
+          cache.objects_per_slab, cache.in_use, cache.free, cache.slabs);
+      }
+    },
+    "acpi" => {
+      use crate::kernel::acpi;
+      match acpi::revision() {
+        Some(revision) => kprintln!("ACPI revision {}", revision),
+        None => kprintln!("No ACPI tables found"),
+      }
+      kprintln!("{:<4} {:>12} {:>8} {:>3} {}", "sig", "address", "length", "rev", "oem");
+      for table in acpi::tables() {
+        kprintln!("{:<4} {:#012x} {:>8} {:>3} {}", table.signature.as_str(), table.address.as_u64(),
+          table.length, table.revision, table.oem_id());
+      }
+      if let Some(hpet) = acpi::hpet() {
+        kprintln!("HPET at {:#x}, {} comparators", hpet.base_address, hpet.num_comparators());
+      }
+      if let Some(processors) = acpi::processor_info() {
+        kprintln!("{} processors", processors.application_processors.len() + 1);
+      }
+    },
+    "cpus" => {
+      use crate::kernel::cpu;
+      kprintln!("{:>3} {:>7} {:<4} {}", "cpu", "apic id", "role", "state");
+      for cpu in cpu::cpus() {
+        kprintln!("{:>3} {:>7} {:<4} {:?}", cpu.index(), cpu.apic_id(),
+          if cpu.is_bootstrap() { "BSP" } else { "AP" }, cpu.state());
+      }
+      kprintln!("{} of {} processors online", cpu::online_count(), cpu::cpus().len());
+    },
+    "shutdown" => {
+      crate::kernel::power::shutdown();
+    },
+    "reboot" => {
+      crate::kernel::power::reboot();
+    },
+    #[cfg(feature = "heap-debug")]
+    "heapallocs" => {
+      use crate::kernel::memory::allocator::debug;
+      let mut count = 0;
+      let mut bytes = 0;
+      let untracked = debug::for_each_allocation(|record| {
+        kprintln!("{:#x} {:>8} bytes from {:x?}", record.ptr, record.size, record.callers);
+        count += 1;
+        bytes += record.size;
+      });
+      kprintln!("{} live allocations, {} bytes ({} untracked)", count, bytes, untracked);
     },
     "exec" => {
       let command = args_iter.next().unwrap();
@@ -0,0 +1,169 @@
+// Per-CPU data
+//
+// Every processor points its GS base at its own `Cpu`. The first field holds
+// the structure's own address, so `current` is a single GS relative load.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use ::acpi::platform::ProcessorState;
+use conquer_once::spin::OnceCell;
+use x86_64::{VirtAddr, registers::model_specific::GsBase};
+
+use crate::{kernel::{acpi, gdt::CpuTables, interrupts::apic::{self, LocalApic}, memory::stack::KernelStack}, kprintln};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CpuState {
+  /// Not started yet.
+  Offline,
+  /// Sent the startup IPIs, waiting for it to check in.
+  Starting,
+  Online,
+  /// Did not come up in time.
+  Failed,
+  /// Marked unusable by the firmware.
+  Disabled,
+}
+
+impl CpuState {
+  fn from_u8(value: u8) -> Self {
+    match value {
+      0 => CpuState::Offline,
+      1 => CpuState::Starting,
+      2 => CpuState::Online,
+      3 => CpuState::Failed,
+      _ => CpuState::Disabled,
+    }
+  }
+}
+
+#[repr(C)]
+pub struct Cpu {
+  /// Address of this structure, must stay the first field.
+  self_address: AtomicU64,
+  index: usize,
+  apic_id: u8,
+  is_bootstrap: bool,
+  state: AtomicU8,
+  stack: OnceCell<KernelStack>,
+  tables: OnceCell<&'static CpuTables>,
+}
+
+static CPUS: OnceCell<Vec<Cpu>> = OnceCell::uninit();
+/// Set once the bootstrap processor loaded its GS base.
+static GS_READY: AtomicBool = AtomicBool::new(false);
+
+impl Cpu {
+  fn new(index: usize, apic_id: u8, is_bootstrap: bool, state: CpuState) -> Self {
+    Cpu {
+      self_address: AtomicU64::new(0),
+      index,
+      apic_id,
+      is_bootstrap,
+      state: AtomicU8::new(state as u8),
+      stack: OnceCell::uninit(),
+      tables: OnceCell::uninit(),
+    }
+  }
+
+  /// Position in `cpus`, the bootstrap processor is always 0.
+  pub fn index(&self) -> usize {
+    self.index
+  }
+
+  pub fn apic_id(&self) -> u8 {
+    self.apic_id
+  }
+
+  pub fn is_bootstrap(&self) -> bool {
+    self.is_bootstrap
+  }
+
+  pub fn state(&self) -> CpuState {
+    CpuState::from_u8(self.state.load(Ordering::Acquire))
+  }
+
+  pub(crate) fn set_state(&self, state: CpuState) {
+    self.state.store(state as u8, Ordering::Release);
+  }
+
+  /// Moves from `current` to `new`, unless someone else changed the state first.
+  pub(crate) fn transition(&self, current: CpuState, new: CpuState) -> bool {
+    self.state.compare_exchange(current as u8, new as u8, Ordering::AcqRel, Ordering::Acquire).is_ok()
+  }
+
+  /// The kernel stack an application processor runs on.
+  pub fn stack(&self) -> Option<&KernelStack> {
+    self.stack.get()
+  }
+
+  pub(crate) fn set_stack(&self, stack: KernelStack) {
+    self.stack.init_once(|| stack);
+  }
+
+  pub(crate) fn tables(&self) -> Option<&'static CpuTables> {
+    self.tables.get().copied()
+  }
+
+  pub(crate) fn set_tables(&self, tables: &'static CpuTables) {
+    self.tables.init_once(|| tables);
+  }
+
+  /// Points the GS base of the running processor at this structure.
+  pub(crate) fn install(&'static self) {
+    let address = self as *const Cpu as u64;
+    self.self_address.store(address, Ordering::Relaxed);
+    GsBase::write(VirtAddr::new(address));
+  }
+}
+
+/// Builds the list of processors from the MADT and installs the per-CPU data
+/// of the bootstrap processor.
+///
+/// Must be called after `apic::init`.
+pub fn init() {
+  let mut cpus = Vec::new();
+  match acpi::processor_info() {
+    Some(info) if apic::is_enabled() => {
+      cpus.push(Cpu::new(0, LocalApic::id(), true, CpuState::Online));
+      for processor in info.application_processors.iter() {
+        let state = match processor.state {
+          ProcessorState::Disabled => CpuState::Disabled,
+          _ => CpuState::Offline,
+        };
+        cpus.push(Cpu::new(cpus.len(), processor.local_apic_id, false, state));
+      }
+    },
+    _ => cpus.push(Cpu::new(0, 0, true, CpuState::Online)),
+  }
+
+  let cpus = CPUS.get_or_init(|| cpus);
+  cpus[0].install();
+  GS_READY.store(true, Ordering::Release);
+
+  kprintln!("[ CPU ] {} processors described, bootstrap processor has APIC id {}.", cpus.len(), cpus[0].apic_id);
+}
+
+/// Every processor the firmware described, started or not.
+pub fn cpus() -> &'static [Cpu] {
+  CPUS.get().map_or(&[], |cpus| cpus.as_slice())
+}
+
+/// Amount of processors currently running.
+pub fn online_count() -> usize {
+  cpus().iter().filter(|cpu| cpu.state() == CpuState::Online).count()
+}
+
+/// Per-CPU data of the running processor.
+///
+/// Application processors install theirs before loading the IDT, but an
+/// exception on the way there still finds a GS base of zero, so that is
+/// checked as well.
+pub fn current() -> Option<&'static Cpu> {
+  if !GS_READY.load(Ordering::Acquire) || GsBase::read().as_u64() == 0 {
+    return None;
+  }
+  let address: u64;
+  unsafe { asm!("mov {}, gs:[0]", out(reg) address, options(nostack, readonly, preserves_flags)) };
+  Some(unsafe { &*(address as *const Cpu) })
+}
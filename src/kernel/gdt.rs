@@ -1,19 +1,43 @@
 // Global Descriptor Table
 
+use alloc::boxed::Box;
 use x86_64::VirtAddr;
 use x86_64::instructions::segmentation::{CS, Segment};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use lazy_static::lazy_static;
-use crate::kprintln;
+use conquer_once::spin::OnceCell;
+use crate::{kernel::memory::stack::{KernelStack, StackError}, kprintln};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+const IST_STACK_COUNT: usize = 3;
+
+const IST_STACK_SIZE: u64 = 4096 * 5;
+
+// Boot time interrupt stacks, used until the heap and vmalloc are up
+const EARLY_STACK_SIZE: usize = 4096 * 5;
+static mut EARLY_STACKS: [[u8; EARLY_STACK_SIZE]; IST_STACK_COUNT] = [[0; EARLY_STACK_SIZE]; IST_STACK_COUNT];
+
+static DOUBLE_FAULT_STACK: OnceCell<KernelStack> = OnceCell::uninit();
+static NMI_STACK: OnceCell<KernelStack> = OnceCell::uninit();
+static MACHINE_CHECK_STACK: OnceCell<KernelStack> = OnceCell::uninit();
+
+// Mutable so the IST entries can be swapped once guarded stacks can be allocated
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 pub fn init() {
   kprintln!("[ GDT ] Initializing Global Descriptor Table...");
 
   use x86_64::instructions::tables::load_tss;
 
+  unsafe {
+    for (index, stack) in EARLY_STACKS.iter().enumerate() {
+      TSS.interrupt_stack_table[index] = VirtAddr::from_ptr(stack) + EARLY_STACK_SIZE;
+    }
+  }
+
   GDT.0.load();
   unsafe {
     CS::set_reg(GDT.1.code_selector);
@@ -22,24 +46,74 @@ pub fn init() {
   kprintln!("[ GDT ] Global Descriptor Table loaded successfully.");
 }
 
-lazy_static! {
-  static ref TSS: TaskStateSegment = {
-    let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-      const STACK_SIZE: usize = 4096 * 5;
-      static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-      let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-      let stack_end = stack_start + STACK_SIZE;
-      stack_end
-    };
-    tss
-  };
+/// Moves the interrupt stacks onto guard-paged kernel stacks, so an overflow
+/// there is caught as well. Must be called once memory is initialized.
+pub fn init_interrupt_stacks() {
+  install_interrupt_stack(&DOUBLE_FAULT_STACK, DOUBLE_FAULT_IST_INDEX, "double fault");
+  install_interrupt_stack(&NMI_STACK, NMI_IST_INDEX, "nmi");
+  install_interrupt_stack(&MACHINE_CHECK_STACK, MACHINE_CHECK_IST_INDEX, "machine check");
+}
+
+fn install_interrupt_stack(cell: &'static OnceCell<KernelStack>, index: u16, name: &'static str) {
+  let stack = cell.get_or_init(|| {
+    KernelStack::new(name, IST_STACK_SIZE)
+      .unwrap_or_else(|_| panic!("Failed to allocate the {} stack", name))
+  });
+
+  // The CPU only reads the entry when the matching exception happens
+  unsafe {
+    TSS.interrupt_stack_table[index as usize] = stack.top();
+  }
+}
+
+/// GDT and TSS of an application processor. A TSS is marked busy once it is
+/// loaded, so no two processors can share one.
+pub struct CpuTables {
+  gdt: GlobalDescriptorTable,
+  selectors: Selectors,
+  _interrupt_stacks: [KernelStack; IST_STACK_COUNT],
+}
+
+/// Builds the tables of an application processor, with its own double fault,
+/// NMI and machine check stacks. They are leaked, processors are never taken
+/// offline.
+pub fn new_cpu_tables() -> Result<&'static CpuTables, StackError> {
+  let double_fault_stack = KernelStack::new("ap double fault", IST_STACK_SIZE)?;
+  let nmi_stack = KernelStack::new("ap nmi", IST_STACK_SIZE)?;
+  let machine_check_stack = KernelStack::new("ap machine check", IST_STACK_SIZE)?;
+
+  let mut tss = TaskStateSegment::new();
+  tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack.top();
+  tss.interrupt_stack_table[NMI_IST_INDEX as usize] = nmi_stack.top();
+  tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = machine_check_stack.top();
+  let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+
+  let mut gdt = GlobalDescriptorTable::new();
+  let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+  let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+  Ok(Box::leak(Box::new(CpuTables {
+    gdt,
+    selectors: Selectors { code_selector, tss_selector },
+    _interrupt_stacks: [double_fault_stack, nmi_stack, machine_check_stack],
+  })))
+}
 
+/// Loads `tables` on the running processor.
+pub fn load_cpu_tables(tables: &'static CpuTables) {
+  use x86_64::instructions::tables::load_tss;
+
+  tables.gdt.load();
+  unsafe {
+    CS::set_reg(tables.selectors.code_selector);
+    load_tss(tables.selectors.tss_selector);
+  }
+}
+
+lazy_static! {
   static ref GDT: (GlobalDescriptorTable, Selectors) = {
     let mut gdt = GlobalDescriptorTable::new();
     let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-    let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &TSS }));
     (gdt, Selectors { code_selector, tss_selector })
   };
 }
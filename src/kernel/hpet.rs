@@ -0,0 +1,258 @@
+// High Precision Event Timer
+//
+// Located through the ACPI HPET table. The main counter runs at a fixed
+// frequency reported by the hardware, which makes it a far better clock
+// source than counting PIT interrupts.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use crate::{kernel::{acpi, interrupts, memory::mmio::{self, CacheMode, MmioRegion}, time}, kprintln};
+
+const REGISTERS_SIZE: usize = 0x400;
+
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+
+const CAP_COUNT_SIZE_64: u64 = 1 << 13;
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_FORCE_32BIT: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1F << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+
+const FEMTOSECONDS_PER_NANOSECOND: u64 = 1_000_000;
+
+fn timer_config(index: u8) -> usize {
+  0x100 + 0x20 * index as usize
+}
+
+fn timer_comparator(index: u8) -> usize {
+  0x108 + 0x20 * index as usize
+}
+
+struct Hpet {
+  registers: MmioRegion,
+  /// Counter tick length in femtoseconds.
+  period_fs: u64,
+  comparators: u8,
+  counter_is_64bit: bool,
+}
+
+static HPET: OnceCell<Mutex<Hpet>> = OnceCell::uninit();
+
+// Kept outside of the lock so the clock can be read from anywhere
+static COUNTER_ADDRESS: AtomicU64 = AtomicU64::new(0);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+static COUNTER_IS_64BIT: OnceCell<bool> = OnceCell::uninit();
+/// Last value seen of a 32-bit counter, extended to 64 bits.
+static LAST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+  OneShot,
+  Periodic,
+}
+
+#[derive(Debug)]
+pub enum HpetError {
+  NotPresent,
+  /// The hardware has no comparator with that index.
+  InvalidComparator(u8),
+  /// The comparator can't fire periodically.
+  PeriodicUnsupported,
+  /// The comparator can't be wired to that IOAPIC input.
+  InvalidRoute(u8),
+}
+
+/// Maps the HPET registers and starts its main counter.
+///
+/// Must be called after `acpi::init`. When the machine has no HPET the
+/// kernel keeps deriving time from the PIT.
+pub fn init() {
+  let info = match acpi::hpet() {
+    Some(info) => info,
+    None => {
+      kprintln!("[ HPET ] No HPET found, keeping the PIT as clock source.");
+      return;
+    }
+  };
+
+  let registers = match unsafe { mmio::ioremap(PhysAddr::new(info.base_address as u64), REGISTERS_SIZE, CacheMode::Uncached) } {
+    Ok(registers) => registers,
+    Err(err) => {
+      kprintln!("[ HPET ] Failed to map the registers: {:?}", err);
+      return;
+    }
+  };
+
+  let capabilities = registers.read::<u64>(CAPABILITIES);
+  let mut hpet = Hpet {
+    period_fs: capabilities >> 32,
+    comparators: ((capabilities >> 8) & 0x1F) as u8 + 1,
+    counter_is_64bit: capabilities & CAP_COUNT_SIZE_64 != 0,
+    registers,
+  };
+  if hpet.period_fs == 0 || hpet.period_fs > 100_000_000 {
+    kprintln!("[ HPET ] Invalid counter period of {} fs, ignoring the HPET.", hpet.period_fs);
+    return;
+  }
+
+  // Stop everything, reset the counter and start it again from zero
+  let config = hpet.registers.read::<u64>(CONFIGURATION) & !(CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+  hpet.registers.write::<u64>(CONFIGURATION, config);
+  for index in 0..hpet.comparators {
+    let timer = hpet.registers.read::<u64>(timer_config(index));
+    hpet.registers.write::<u64>(timer_config(index), timer & !(TIMER_INTERRUPT_ENABLE | TIMER_FSB_ENABLE));
+  }
+  hpet.registers.write::<u64>(MAIN_COUNTER, 0);
+
+  COUNTER_ADDRESS.store(hpet.registers.virt_addr().as_u64() + MAIN_COUNTER as u64, Ordering::Release);
+  PERIOD_FS.store(hpet.period_fs, Ordering::Release);
+  COUNTER_IS_64BIT.init_once(|| hpet.counter_is_64bit);
+
+  kprintln!("[ HPET ] {} MHz counter ({} bits) with {} comparators.",
+    frequency_of(hpet.period_fs) / 1_000_000, if hpet.counter_is_64bit { 64 } else { 32 }, hpet.comparators);
+
+  interrupts::without_interrupts(|| {
+    hpet.registers.write::<u64>(CONFIGURATION, config | CONFIG_ENABLE);
+    time::use_hpet();
+  });
+  HPET.init_once(|| Mutex::new(hpet));
+}
+
+fn frequency_of(period_fs: u64) -> u64 {
+  1_000_000_000_000_000 / period_fs
+}
+
+/// Whether the HPET is present and counting.
+pub fn is_available() -> bool {
+  COUNTER_ADDRESS.load(Ordering::Acquire) != 0
+}
+
+/// Counter frequency in Hz.
+pub fn frequency() -> Option<u64> {
+  match PERIOD_FS.load(Ordering::Acquire) {
+    0 => None,
+    period_fs => Some(frequency_of(period_fs)),
+  }
+}
+
+/// Raw value of the main counter.
+///
+/// A 32-bit counter is extended to 64 bits, which only works if it is read at
+/// least once per wrap around (about five minutes at 14.3 MHz).
+pub fn counter() -> Option<u64> {
+  let address = COUNTER_ADDRESS.load(Ordering::Acquire);
+  if address == 0 {
+    return None;
+  }
+
+  if *COUNTER_IS_64BIT.get()? {
+    return Some(unsafe { (address as *const u64).read_volatile() });
+  }
+
+  let low = unsafe { (address as *const u32).read_volatile() } as u64;
+  let mut last = LAST_COUNTER.load(Ordering::Acquire);
+  loop {
+    let mut value = (last & !0xFFFF_FFFF) | low;
+    if value < last {
+      value += 1 << 32;
+    }
+    match LAST_COUNTER.compare_exchange_weak(last, value, Ordering::AcqRel, Ordering::Acquire) {
+      Ok(_) => return Some(value),
+      // Someone else read a newer value, which is at least as recent
+      Err(current) if current >= value => return Some(current),
+      Err(current) => last = current,
+    }
+  }
+}
+
+/// Nanoseconds since the HPET was started. Monotonic.
+pub fn nanoseconds() -> Option<u64> {
+  let ticks = counter()?;
+  let period_fs = PERIOD_FS.load(Ordering::Acquire);
+  Some((ticks as u128 * period_fs as u128 / FEMTOSECONDS_PER_NANOSECOND as u128) as u64)
+}
+
+/// Amount of comparators the HPET has.
+pub fn comparators() -> u8 {
+  HPET.get().map_or(0, |hpet| hpet.lock().comparators)
+}
+
+/// Bitmap of the IOAPIC inputs comparator `index` can be routed to.
+pub fn route_capabilities(index: u8) -> Result<u32, HpetError> {
+  let hpet = HPET.get().ok_or(HpetError::NotPresent)?;
+  let hpet = hpet.lock();
+  if index >= hpet.comparators {
+    return Err(HpetError::InvalidComparator(index));
+  }
+  Ok((hpet.registers.read::<u64>(timer_config(index)) >> 32) as u32)
+}
+
+/// Makes comparator `index` raise IOAPIC input `ioapic_input` after
+/// `interval_ns`, once or every `interval_ns` depending on `mode`.
+///
+/// The caller is responsible for routing the IOAPIC input to a handler.
+pub fn arm_comparator(index: u8, mode: TimerMode, interval_ns: u64, ioapic_input: u8) -> Result<(), HpetError> {
+  let hpet = HPET.get().ok_or(HpetError::NotPresent)?;
+  interrupts::without_interrupts(|| {
+    let mut hpet = hpet.lock();
+    if index >= hpet.comparators {
+      return Err(HpetError::InvalidComparator(index));
+    }
+
+    let mut config = hpet.registers.read::<u64>(timer_config(index));
+    if mode == TimerMode::Periodic && config & TIMER_PERIODIC_CAPABLE == 0 {
+      return Err(HpetError::PeriodicUnsupported);
+    }
+    if ioapic_input >= 32 || (config >> 32) & (1 << ioapic_input) == 0 {
+      return Err(HpetError::InvalidRoute(ioapic_input));
+    }
+
+    let ticks = (interval_ns as u128 * FEMTOSECONDS_PER_NANOSECOND as u128 / hpet.period_fs as u128).max(1) as u64;
+
+    config &= !(TIMER_ROUTE_MASK | TIMER_PERIODIC | TIMER_LEVEL_TRIGGERED | TIMER_FORCE_32BIT | TIMER_FSB_ENABLE);
+    config |= (ioapic_input as u64) << TIMER_ROUTE_SHIFT | TIMER_INTERRUPT_ENABLE;
+    let now = hpet.registers.read::<u64>(MAIN_COUNTER);
+
+    match mode {
+      TimerMode::OneShot => {
+        hpet.registers.write::<u64>(timer_config(index), config);
+        hpet.registers.write::<u64>(timer_comparator(index), now.wrapping_add(ticks));
+      },
+      TimerMode::Periodic => {
+        // With VALUE_SET the first write sets the comparator and the second
+        // one the period it is advanced by
+        hpet.registers.write::<u64>(timer_config(index), config | TIMER_PERIODIC | TIMER_VALUE_SET);
+        hpet.registers.write::<u64>(timer_comparator(index), now.wrapping_add(ticks));
+        hpet.registers.write::<u64>(timer_comparator(index), ticks);
+      },
+    }
+    Ok(())
+  })
+}
+
+/// Stops comparator `index` from raising interrupts.
+pub fn disarm_comparator(index: u8) -> Result<(), HpetError> {
+  let hpet = HPET.get().ok_or(HpetError::NotPresent)?;
+  interrupts::without_interrupts(|| {
+    let mut hpet = hpet.lock();
+    if index >= hpet.comparators {
+      return Err(HpetError::InvalidComparator(index));
+    }
+    let config = hpet.registers.read::<u64>(timer_config(index));
+    hpet.registers.write::<u64>(timer_config(index), config & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC));
+    Ok(())
+  })
+}
@@ -0,0 +1,306 @@
+// Local APIC and IOAPIC support
+//
+// When the MADT describes an APIC the legacy PICs are masked and every ISA
+// interrupt is routed through the IOAPIC to the bootstrap processor, on the
+// same vectors the PICs used. Without one the kernel keeps using the PICs.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use ::acpi::{InterruptModel, platform::interrupt::{Apic, Polarity, TriggerMode}};
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::{PhysAddr, registers::model_specific::Msr, structures::idt::InterruptStackFrame};
+
+use crate::{kernel::{acpi, memory::mmio::{self, CacheMode, MmioRegion}}, kprintln};
+use super::{InterruptIndex, PIC_1_OFFSET, PICS};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+// Local APIC registers, as offsets into its MMIO page
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SVR: usize = 0xF0;
+const LAPIC_ISR: usize = 0x100;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_SIZE: usize = 0x400;
+
+const SVR_ENABLE: u32 = 1 << 8;
+
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+// IOAPIC registers, accessed indirectly through IOREGSEL/IOWIN
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_SIZE: usize = 0x20;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+
+static APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Virtual address of the local APIC registers, read lock-free by the EOI path.
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+static LAPIC_REGION: OnceCell<MmioRegion> = OnceCell::uninit();
+
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+/// Returns whether interrupts are delivered through the APIC instead of the PICs.
+pub fn is_enabled() -> bool {
+  APIC_ENABLED.load(Ordering::Acquire)
+}
+
+/// The local APIC of the running processor.
+///
+/// Every processor sees its own local APIC at the same address, so a single
+/// mapping serves all of them.
+pub struct LocalApic;
+
+impl LocalApic {
+  fn base() -> u64 {
+    let base = LAPIC_BASE.load(Ordering::Acquire);
+    assert!(base != 0, "local APIC not mapped");
+    base
+  }
+
+  pub fn read(register: usize) -> u32 {
+    unsafe { ((Self::base() + register as u64) as *const u32).read_volatile() }
+  }
+
+  pub fn write(register: usize, value: u32) {
+    unsafe { ((Self::base() + register as u64) as *mut u32).write_volatile(value) }
+  }
+
+  pub fn id() -> u8 {
+    (Self::read(LAPIC_ID) >> 24) as u8
+  }
+
+  pub fn end_of_interrupt() {
+    Self::write(LAPIC_EOI, 0);
+  }
+
+  /// Whether `vector` was delivered by the local APIC and awaits its EOI.
+  pub fn is_in_service(vector: u8) -> bool {
+    let register = LAPIC_ISR + (vector as usize / 32) * 0x10;
+    Self::read(register) & (1 << (vector % 32)) != 0
+  }
+
+  /// Writes the interrupt command register and waits until the IPI is sent.
+  fn send_ipi(destination: u8, command: u32) {
+    Self::write(LAPIC_ICR_HIGH, (destination as u32) << 24);
+    Self::write(LAPIC_ICR_LOW, command);
+    while Self::read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+      core::hint::spin_loop();
+    }
+  }
+
+  /// Sends an INIT IPI, which puts the processor into wait-for-SIPI.
+  pub fn send_init(destination: u8) {
+    Self::send_ipi(destination, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+  }
+
+  /// Sends a startup IPI, the processor starts in real mode at `vector * 0x1000`.
+  pub fn send_startup(destination: u8, vector: u8) {
+    Self::send_ipi(destination, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | vector as u32);
+  }
+
+  /// Enables the local APIC of the running processor.
+  fn enable() {
+    unsafe {
+      let mut base = Msr::new(IA32_APIC_BASE);
+      let value = base.read();
+      base.write(value | APIC_BASE_ENABLE);
+    }
+    // Accept every priority and point spurious interrupts at their own vector
+    Self::write(LAPIC_TPR, 0);
+    Self::write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+  }
+}
+
+struct IoApic {
+  id: u8,
+  region: MmioRegion,
+  gsi_base: u32,
+  entries: u32,
+}
+
+impl IoApic {
+  fn read(&mut self, register: u32) -> u32 {
+    self.region.write::<u32>(IOREGSEL, register);
+    self.region.read::<u32>(IOWIN)
+  }
+
+  fn write(&mut self, register: u32, value: u32) {
+    self.region.write::<u32>(IOREGSEL, register);
+    self.region.write::<u32>(IOWIN, value);
+  }
+
+  fn handles(&self, gsi: u32) -> bool {
+    gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+  }
+
+  fn read_redirection(&mut self, gsi: u32) -> u64 {
+    let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+    let low = self.read(register) as u64;
+    let high = self.read(register + 1) as u64;
+    high << 32 | low
+  }
+
+  fn write_redirection(&mut self, gsi: u32, entry: u64) {
+    let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+    // Mask first so the entry never fires half written
+    self.write(register, REDIRECTION_MASKED as u32);
+    self.write(register + 1, (entry >> 32) as u32);
+    self.write(register, entry as u32);
+  }
+}
+
+/// Where an ISA IRQ ends up on the IOAPIC and how it is signalled.
+#[derive(Debug, Clone, Copy)]
+struct IsaRoute {
+  gsi: u32,
+  active_low: bool,
+  level_triggered: bool,
+}
+
+fn isa_route(apic: &Apic, irq: u8) -> IsaRoute {
+  // ISA interrupts are edge triggered and active high unless overridden
+  let mut route = IsaRoute { gsi: irq as u32, active_low: false, level_triggered: false };
+  if let Some(over) = apic.interrupt_source_overrides.iter().find(|o| o.isa_source == irq) {
+    route.gsi = over.global_system_interrupt;
+    route.active_low = matches!(over.polarity, Polarity::ActiveLow);
+    route.level_triggered = matches!(over.trigger_mode, TriggerMode::Level);
+  }
+  route
+}
+
+/// Switches interrupt delivery over to the APIC if the MADT describes one.
+///
+/// Must be called after `acpi::init`, with the PICs already initialized.
+pub fn init() {
+  let apic = match acpi::interrupt_model() {
+    Some(InterruptModel::Apic(apic)) => apic,
+    _ => {
+      kprintln!("[ APIC ] No APIC described by ACPI, staying on the 8259 PICs.");
+      return;
+    }
+  };
+
+  kprintln!("[ APIC ] Initializing Local APIC and IOAPICs...");
+
+  let lapic = match unsafe { mmio::ioremap(PhysAddr::new(apic.local_apic_address), LAPIC_SIZE, CacheMode::Uncached) } {
+    Ok(region) => region,
+    Err(err) => {
+      kprintln!("[ APIC ] Failed to map the Local APIC: {:?}", err);
+      return;
+    }
+  };
+  LAPIC_BASE.store(lapic.virt_addr().as_u64(), Ordering::Release);
+  LAPIC_REGION.init_once(|| lapic);
+
+  {
+    let mut io_apics = IO_APICS.lock();
+    for info in apic.io_apics.iter() {
+      let region = match unsafe { mmio::ioremap(PhysAddr::new(info.address as u64), IOAPIC_SIZE, CacheMode::Uncached) } {
+        Ok(region) => region,
+        Err(err) => {
+          kprintln!("[ APIC ] Failed to map IOAPIC {}: {:?}", info.id, err);
+          continue;
+        }
+      };
+      let mut io_apic = IoApic { id: info.id, region, gsi_base: info.global_system_interrupt_base, entries: 0 };
+      io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+
+      // Start from a clean slate, everything masked
+      for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+        io_apic.write_redirection(gsi, REDIRECTION_MASKED);
+      }
+      kprintln!("[ APIC ] IOAPIC {} handles GSIs {}-{}", io_apic.id, io_apic.gsi_base,
+        io_apic.gsi_base + io_apic.entries - 1);
+      io_apics.push(io_apic);
+    }
+    if io_apics.is_empty() {
+      kprintln!("[ APIC ] No usable IOAPIC, staying on the 8259 PICs.");
+      return;
+    }
+  }
+
+  super::without_interrupts(|| {
+    LocalApic::enable();
+
+    let destination = LocalApic::id();
+    for &index in [InterruptIndex::Timer, InterruptIndex::Keyboard, InterruptIndex::RTC].iter() {
+      route_isa_irq(apic, index, destination, false);
+    }
+    // No disk driver handles these yet, keep them masked until one does
+    for &index in [InterruptIndex::PrimaryATAHardDisk, InterruptIndex::SecondaryATAHardDisk].iter() {
+      route_isa_irq(apic, index, destination, true);
+    }
+
+    // The PICs stay remapped. Masked, they still raise spurious IRQ 7 and 15,
+    // see `spurious` for those.
+    unsafe { PICS.lock().write_masks(0xFF, 0xFF) };
+    APIC_ENABLED.store(true, Ordering::Release);
+  });
+
+  kprintln!("[ APIC ] Local APIC {} enabled, PICs masked.", LocalApic::id());
+}
+
+/// Enables the local APIC of an application processor. Device interrupts
+/// stay routed to the bootstrap processor.
+pub fn init_ap() {
+  LocalApic::enable();
+}
+
+fn route_isa_irq(apic: &Apic, index: InterruptIndex, destination: u8, masked: bool) {
+  let irq = index.as_u8() - PIC_1_OFFSET;
+  let route = isa_route(apic, irq);
+
+  let mut entry = index.as_u8() as u64 | (destination as u64) << 56;
+  if route.active_low {
+    entry |= REDIRECTION_ACTIVE_LOW;
+  }
+  if route.level_triggered {
+    entry |= REDIRECTION_LEVEL;
+  }
+  if masked {
+    entry |= REDIRECTION_MASKED;
+  }
+
+  let mut io_apics = IO_APICS.lock();
+  match io_apics.iter_mut().find(|io_apic| io_apic.handles(route.gsi)) {
+    Some(io_apic) => io_apic.write_redirection(route.gsi, entry),
+    None => kprintln!("[ APIC ] No IOAPIC handles GSI {} (IRQ {})", route.gsi, irq),
+  }
+}
+
+/// Masks or unmasks the IOAPIC entry an ISA IRQ was routed to.
+pub fn set_isa_irq_masked(irq: u8, masked: bool) {
+  let apic = match acpi::interrupt_model() {
+    Some(InterruptModel::Apic(apic)) => apic,
+    _ => return,
+  };
+  let route = isa_route(apic, irq);
+
+  super::without_interrupts(|| {
+    let mut io_apics = IO_APICS.lock();
+    if let Some(io_apic) = io_apics.iter_mut().find(|io_apic| io_apic.handles(route.gsi)) {
+      let entry = io_apic.read_redirection(route.gsi);
+      let entry = if masked { entry | REDIRECTION_MASKED } else { entry & !REDIRECTION_MASKED };
+      io_apic.write_redirection(route.gsi, entry);
+    }
+  });
+}
+
+/// Spurious interrupts must not be acknowledged.
+pub extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
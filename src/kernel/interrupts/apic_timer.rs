@@ -0,0 +1,156 @@
+// Local APIC timer
+//
+// Calibrated against the TSC at boot. Rather than ticking every millisecond
+// like the PIT it is only armed for the next deadline a sleep waits on, see
+// `time::program_next_deadline`, so an idle CPU stays halted. When the CPU
+// supports it deadlines are programmed in TSC-deadline mode.
+
+use core::{arch::x86_64::{__cpuid, _mm_mfence}, sync::atomic::{AtomicBool, AtomicU64, Ordering}};
+use x86_64::{registers::model_specific::Msr, structures::idt::InterruptStackFrame};
+
+use crate::{kernel::time, kprintln};
+use super::apic::{self, LocalApic};
+
+pub const TIMER_VECTOR: u8 = 0x30;
+
+// Local APIC timer registers
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_INITIAL_COUNT: usize = 0x380;
+const LAPIC_CURRENT_COUNT: usize = 0x390;
+const LAPIC_DIVIDE_CONFIG: usize = 0x3E0;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_ONE_SHOT: u32 = 0b00 << 17;
+const LVT_PERIODIC: u32 = 0b01 << 17;
+const LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+
+const DIVIDE_BY_16: u32 = 0b0011;
+
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+const CALIBRATION_MS: u64 = 10;
+const NANOSECONDS_PER_MS: u64 = 1_000_000;
+
+/// Timer ticks per millisecond with `DIVIDE_BY_16`, zero until calibrated.
+static TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Calibrates the local APIC timer and stops the PIT tick if possible.
+///
+/// Must be called after `apic::init` and `time::calibrate_tsc`.
+pub fn init() {
+  if !apic::is_enabled() {
+    kprintln!("[ APIC ] No local APIC, the PIT keeps waking sleepers up.");
+    return;
+  }
+  if time::tsc_frequency().is_none() {
+    kprintln!("[ APIC ] TSC not calibrated, can't calibrate the local APIC timer.");
+    return;
+  }
+
+  let ticks_per_ms = super::without_interrupts(calibrate);
+  if ticks_per_ms == 0 {
+    kprintln!("[ APIC ] Local APIC timer does not count, the PIT keeps waking sleepers up.");
+    return;
+  }
+  TICKS_PER_MS.store(ticks_per_ms, Ordering::Release);
+
+  // CPUID.01H:ECX bit 24
+  let tsc_deadline = unsafe { __cpuid(1) }.ecx & (1 << 24) != 0;
+  TSC_DEADLINE.store(tsc_deadline, Ordering::Release);
+  ACTIVE.store(true, Ordering::Release);
+
+  kprintln!("[ APIC ] Local APIC timer runs at {} kHz{}.", ticks_per_ms,
+    if tsc_deadline { ", TSC-deadline mode supported" } else { "" });
+
+  if time::stop_pit_tick() {
+    kprintln!("[ APIC ] PIT tick stopped.");
+  } else {
+    kprintln!("[ APIC ] Keeping the PIT tick, nothing else keeps time.");
+  }
+}
+
+/// Counts how far the timer gets in `CALIBRATION_MS`.
+fn calibrate() -> u64 {
+  LocalApic::write(LAPIC_DIVIDE_CONFIG, DIVIDE_BY_16);
+  LocalApic::write(LAPIC_LVT_TIMER, LVT_MASKED | LVT_ONE_SHOT | TIMER_VECTOR as u32);
+
+  let start = time::monotonic_ns();
+  LocalApic::write(LAPIC_INITIAL_COUNT, u32::MAX);
+  while time::monotonic_ns() - start < CALIBRATION_MS * NANOSECONDS_PER_MS {
+    core::hint::spin_loop();
+  }
+  let remaining = LocalApic::read(LAPIC_CURRENT_COUNT);
+  LocalApic::write(LAPIC_INITIAL_COUNT, 0);
+
+  (u32::MAX - remaining) as u64 / CALIBRATION_MS
+}
+
+/// Whether the local APIC timer is calibrated and wakes sleepers up.
+pub fn is_active() -> bool {
+  ACTIVE.load(Ordering::Acquire)
+}
+
+/// Whether one-shot deadlines are programmed in TSC-deadline mode.
+pub fn uses_tsc_deadline() -> bool {
+  TSC_DEADLINE.load(Ordering::Acquire)
+}
+
+/// Timer frequency in Hz, once calibrated.
+pub fn frequency() -> Option<u64> {
+  match TICKS_PER_MS.load(Ordering::Acquire) {
+    0 => None,
+    ticks_per_ms => Some(ticks_per_ms * 1000),
+  }
+}
+
+/// Raises one interrupt once `time::monotonic_ns` reaches `deadline`, or
+/// right away if it already did. Replaces whatever was armed before.
+pub fn arm_oneshot(deadline: u64) {
+  if uses_tsc_deadline() {
+    if let Some(tsc) = time::tsc_at(deadline) {
+      LocalApic::write(LAPIC_LVT_TIMER, LVT_TSC_DEADLINE | TIMER_VECTOR as u32);
+      // The LVT write must land before the deadline is set
+      unsafe {
+        _mm_mfence();
+        Msr::new(IA32_TSC_DEADLINE).write(tsc.max(1));
+      }
+      return;
+    }
+  }
+
+  let delay = deadline.saturating_sub(time::monotonic_ns());
+  start_counting(LVT_ONE_SHOT, delay);
+}
+
+/// Raises an interrupt every `interval_ns`, until `stop` or `arm_oneshot`.
+pub fn start_periodic(interval_ns: u64) {
+  start_counting(LVT_PERIODIC, interval_ns);
+}
+
+fn start_counting(mode: u32, interval_ns: u64) {
+  let ticks_per_ms = TICKS_PER_MS.load(Ordering::Acquire);
+  let ticks = (interval_ns as u128 * ticks_per_ms as u128 / NANOSECONDS_PER_MS as u128)
+    .max(1)
+    .min(u32::MAX as u128) as u32;
+
+  LocalApic::write(LAPIC_DIVIDE_CONFIG, DIVIDE_BY_16);
+  LocalApic::write(LAPIC_LVT_TIMER, mode | TIMER_VECTOR as u32);
+  LocalApic::write(LAPIC_INITIAL_COUNT, ticks);
+}
+
+/// Disarms the timer.
+pub fn stop() {
+  LocalApic::write(LAPIC_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+  LocalApic::write(LAPIC_INITIAL_COUNT, 0);
+  if uses_tsc_deadline() {
+    unsafe { Msr::new(IA32_TSC_DEADLINE).write(0) };
+  }
+}
+
+pub extern "x86-interrupt" fn apic_timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+  time::expire_timers();
+
+  LocalApic::end_of_interrupt();
+}
@@ -1,7 +1,16 @@
-use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::{registers::control::Cr2, structures::idt::InterruptStackFrame};
+use crate::kernel::memory::stack;
 
 pub extern "x86-interrupt" fn double_fault_handler(
   stack_frame: InterruptStackFrame, _error_code: u64
 ) -> ! {
+  // Overflowing a stack faults on its guard page, and pushing the page fault
+  // frame onto that same stack is what turns it into a double fault
+  let overflowed = stack::guard_page_owner(Cr2::read())
+    .or_else(|| stack::guard_page_owner(stack_frame.stack_pointer - 1u64));
+  if let Some(context) = overflowed {
+    panic!("kernel stack overflow in {} at {:?}", context, stack_frame.instruction_pointer);
+  }
+
   panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame)
-}
\ No newline at end of file
+}
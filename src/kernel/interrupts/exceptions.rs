@@ -0,0 +1,279 @@
+// Architectural exception handlers
+//
+// Every exception without a dedicated handler enters through a small stub
+// that pushes a dummy error code when the CPU doesn't push one, then the
+// vector, then every general purpose register. `exception_dispatch` gets the
+// whole thing as an `ExceptionFrame`, so the report shows the state the CPU
+// was in when it faulted rather than the state of a Rust handler.
+
+use core::fmt::{self, Write};
+use x86_64::{VirtAddr, registers::control::{Cr0, Cr2, Cr3, Cr4}, structures::idt::InterruptDescriptorTable};
+
+use crate::kernel::{cpu, gdt, vga::WRITER};
+
+global_asm!(r#"
+.pushsection .text.exceptions, "ax"
+
+.macro exception_stub name, vector, has_error_code
+.global \name
+\name:
+.if \has_error_code == 0
+    push 0
+.endif
+    push \vector
+    jmp exception_common
+.endm
+
+exception_stub exception_divide_error, 0, 0
+exception_stub exception_debug, 1, 0
+exception_stub exception_non_maskable_interrupt, 2, 0
+exception_stub exception_overflow, 4, 0
+exception_stub exception_bound_range_exceeded, 5, 0
+exception_stub exception_invalid_opcode, 6, 0
+exception_stub exception_device_not_available, 7, 0
+exception_stub exception_invalid_tss, 10, 1
+exception_stub exception_segment_not_present, 11, 1
+exception_stub exception_stack_segment_fault, 12, 1
+exception_stub exception_general_protection_fault, 13, 1
+exception_stub exception_x87_floating_point, 16, 0
+exception_stub exception_alignment_check, 17, 1
+exception_stub exception_machine_check, 18, 0
+exception_stub exception_simd_floating_point, 19, 0
+exception_stub exception_virtualization, 20, 0
+exception_stub exception_security_exception, 30, 1
+
+// Saves the registers in the order `ExceptionFrame` expects them
+exception_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    // The CPU aligned the stack before pushing its frame and we pushed an even
+    // amount of quad words since, so it is still 16 byte aligned here
+    mov rdi, rsp
+    cld
+    call exception_dispatch
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+
+    // Vector and error code
+    add rsp, 16
+    iretq
+
+.popsection
+"#);
+
+extern "C" {
+  fn exception_divide_error();
+  fn exception_debug();
+  fn exception_non_maskable_interrupt();
+  fn exception_overflow();
+  fn exception_bound_range_exceeded();
+  fn exception_invalid_opcode();
+  fn exception_device_not_available();
+  fn exception_invalid_tss();
+  fn exception_segment_not_present();
+  fn exception_stack_segment_fault();
+  fn exception_general_protection_fault();
+  fn exception_x87_floating_point();
+  fn exception_alignment_check();
+  fn exception_machine_check();
+  fn exception_simd_floating_point();
+  fn exception_virtualization();
+  fn exception_security_exception();
+}
+
+const DEBUG: u64 = 1;
+const NON_MASKABLE_INTERRUPT: u64 = 2;
+const INVALID_TSS: u64 = 10;
+const SEGMENT_NOT_PRESENT: u64 = 11;
+const STACK_SEGMENT_FAULT: u64 = 12;
+const GENERAL_PROTECTION_FAULT: u64 = 13;
+const ALIGNMENT_CHECK: u64 = 17;
+const MACHINE_CHECK: u64 = 18;
+const SECURITY_EXCEPTION: u64 = 30;
+
+/// Registers saved by `exception_common`, followed by what the stub and the
+/// CPU pushed.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ExceptionFrame {
+  pub r15: u64,
+  pub r14: u64,
+  pub r13: u64,
+  pub r12: u64,
+  pub r11: u64,
+  pub r10: u64,
+  pub r9: u64,
+  pub r8: u64,
+  pub rbp: u64,
+  pub rdi: u64,
+  pub rsi: u64,
+  pub rdx: u64,
+  pub rcx: u64,
+  pub rbx: u64,
+  pub rax: u64,
+  pub vector: u64,
+  /// Zero for exceptions that don't push one.
+  pub error_code: u64,
+  pub rip: u64,
+  pub cs: u64,
+  pub rflags: u64,
+  pub rsp: u64,
+  pub ss: u64,
+}
+
+impl fmt::Display for ExceptionFrame {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}", self.rax, self.rbx, self.rcx, self.rdx)?;
+    writeln!(f, "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}", self.rsi, self.rdi, self.rbp, self.rsp)?;
+    writeln!(f, "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}", self.r8, self.r9, self.r10, self.r11)?;
+    writeln!(f, "R12={:016x} R13={:016x} R14={:016x} R15={:016x}", self.r12, self.r13, self.r14, self.r15)?;
+    write!(f, "RIP={:016x} RFLAGS={:016x} CS={:04x} SS={:04x}", self.rip, self.rflags, self.cs, self.ss)
+  }
+}
+
+/// Name and mnemonic of an exception vector.
+fn describe(vector: u64) -> (&'static str, &'static str) {
+  match vector {
+    0 => ("DIVIDE ERROR", "#DE"),
+    1 => ("DEBUG", "#DB"),
+    2 => ("NON-MASKABLE INTERRUPT", "NMI"),
+    4 => ("OVERFLOW", "#OF"),
+    5 => ("BOUND RANGE EXCEEDED", "#BR"),
+    6 => ("INVALID OPCODE", "#UD"),
+    7 => ("DEVICE NOT AVAILABLE", "#NM"),
+    10 => ("INVALID TSS", "#TS"),
+    11 => ("SEGMENT NOT PRESENT", "#NP"),
+    12 => ("STACK SEGMENT FAULT", "#SS"),
+    13 => ("GENERAL PROTECTION FAULT", "#GP"),
+    16 => ("X87 FLOATING POINT", "#MF"),
+    17 => ("ALIGNMENT CHECK", "#AC"),
+    18 => ("MACHINE CHECK", "#MC"),
+    19 => ("SIMD FLOATING POINT", "#XM"),
+    20 => ("VIRTUALIZATION", "#VE"),
+    30 => ("SECURITY EXCEPTION", "#SX"),
+    _ => ("UNKNOWN", "#??"),
+  }
+}
+
+/// Decodes the selector error code pushed by #TS, #NP, #SS and #GP.
+fn describe_selector_error(out: &mut impl Write, error_code: u64) -> fmt::Result {
+  if error_code == 0 {
+    return writeln!(out, "Error Code: 0 (not segment related)");
+  }
+
+  let table = match (error_code >> 1) & 0b11 {
+    0b00 => "GDT",
+    0b10 => "LDT",
+    _ => "IDT",
+  };
+  writeln!(out, "Error Code: {:#x} ({} index {}{})", error_code, table, (error_code >> 3) & 0x1FFF,
+    if error_code & 1 != 0 { ", external event" } else { "" })
+}
+
+/// Writes the exception, its error code and the registers to `out`.
+fn report(out: &mut impl Write, frame: &ExceptionFrame) -> fmt::Result {
+  let (name, mnemonic) = describe(frame.vector);
+  let cpu = cpu::current().map_or(0, |cpu| cpu.index());
+
+  writeln!(out, "EXCEPTION: {} ({}) on CPU {}", name, mnemonic, cpu)?;
+  match frame.vector {
+    INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+      describe_selector_error(out, frame.error_code)?;
+    },
+    ALIGNMENT_CHECK => writeln!(out, "Error Code: {:#x}", frame.error_code)?,
+    SECURITY_EXCEPTION => writeln!(out, "Error Code: {:#x}{}", frame.error_code,
+      if frame.error_code == 1 { " (INIT redirected)" } else { "" })?,
+    _ => {},
+  }
+  writeln!(out, "{}", frame)?;
+  writeln!(out, "CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}", Cr0::read_raw(), Cr2::read().as_u64(),
+    Cr3::read_raw().0.start_address().as_u64(), Cr4::read_raw())
+}
+
+/// Common Rust side of every exception stub.
+#[no_mangle]
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+  let (_, mnemonic) = describe(frame.vector);
+
+  match frame.vector {
+    // Neither disabling interrupts nor anything else keeps these out of the
+    // printing code, so waiting for the screen could wait forever. No report
+    // then, the interrupted code holds the lock.
+    DEBUG | NON_MASKABLE_INTERRUPT | MACHINE_CHECK => {
+      if let Some(mut writer) = WRITER.try_lock() {
+        report(&mut *writer, frame).ok();
+        if frame.vector == MACHINE_CHECK {
+          writeln!(writer, "kernel oops: {} at {:#x}", mnemonic, frame.rip).ok();
+        }
+      }
+
+      // The machine can't be trusted any more, but the panic handler would
+      // wait for the screen as well
+      if frame.vector == MACHINE_CHECK {
+        loop {
+          x86_64::instructions::interrupts::disable();
+          x86_64::instructions::hlt();
+        }
+      }
+    },
+    _ => {
+      report(&mut *WRITER.lock(), frame).ok();
+      panic!("kernel oops: {} at {:#x}", mnemonic, frame.rip);
+    },
+  }
+}
+
+/// Points every exception without a dedicated handler at its stub.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+  let address = |stub: unsafe extern "C" fn()| VirtAddr::new(stub as usize as u64);
+
+  unsafe {
+    idt.divide_error.set_handler_addr(address(exception_divide_error));
+    idt.debug.set_handler_addr(address(exception_debug));
+    idt.non_maskable_interrupt.set_handler_addr(address(exception_non_maskable_interrupt))
+      .set_stack_index(gdt::NMI_IST_INDEX);
+    idt.overflow.set_handler_addr(address(exception_overflow));
+    idt.bound_range_exceeded.set_handler_addr(address(exception_bound_range_exceeded));
+    idt.invalid_opcode.set_handler_addr(address(exception_invalid_opcode));
+    idt.device_not_available.set_handler_addr(address(exception_device_not_available));
+    idt.invalid_tss.set_handler_addr(address(exception_invalid_tss));
+    idt.segment_not_present.set_handler_addr(address(exception_segment_not_present));
+    idt.stack_segment_fault.set_handler_addr(address(exception_stack_segment_fault));
+    idt.general_protection_fault.set_handler_addr(address(exception_general_protection_fault));
+    idt.x87_floating_point.set_handler_addr(address(exception_x87_floating_point));
+    idt.alignment_check.set_handler_addr(address(exception_alignment_check));
+    idt.machine_check.set_handler_addr(address(exception_machine_check))
+      .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+    idt.simd_floating_point.set_handler_addr(address(exception_simd_floating_point));
+    idt.virtualization.set_handler_addr(address(exception_virtualization));
+    idt.security_exception.set_handler_addr(address(exception_security_exception));
+  }
+}
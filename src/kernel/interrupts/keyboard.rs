@@ -1,6 +1,6 @@
 use x86_64::structures::idt::InterruptStackFrame;
 use lazy_static::lazy_static;
-use kernel::interrupts::{InterruptIndex, PICS};
+use kernel::interrupts::{self, InterruptIndex};
 
 use crate::kernel;
 
@@ -23,8 +23,5 @@ pub extern "x86-interrupt" fn keyboard_interrupt_handler(
   let scancode: u8 = unsafe { port.read() };
   kernel::task::keyboard::add_scancode(scancode); // new
 
-  unsafe {
-    PICS.lock()
-      .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-  }
+  interrupts::end_of_interrupt(InterruptIndex::Keyboard);
 }
\ No newline at end of file
@@ -12,7 +12,11 @@ pub mod timer;
 pub mod page_fault;
 pub mod breakpoint;
 pub mod double_fault;
+pub mod exceptions;
 pub mod rtc;
+pub mod apic;
+pub mod apic_timer;
+pub mod spurious;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -23,6 +27,7 @@ pub static PICS: spin::Mutex<ChainedPics> =
 lazy_static! {
   static ref IDT: InterruptDescriptorTable = {
     let mut idt = InterruptDescriptorTable::new();
+    exceptions::install(&mut idt);
     idt.breakpoint.set_handler_fn(breakpoint::breakpoint_handler);
     idt.page_fault.set_handler_fn(page_fault::page_fault_handler);
     unsafe {
@@ -39,6 +44,18 @@ lazy_static! {
     idt[InterruptIndex::RTC.as_usize()]
       .set_handler_fn(rtc::rtc_interrupt_handler);
 
+    idt[apic_timer::TIMER_VECTOR as usize]
+      .set_handler_fn(apic_timer::apic_timer_interrupt_handler);
+
+    idt[spurious::PIC_1_SPURIOUS_VECTOR as usize]
+      .set_handler_fn(spurious::pic_1_spurious_handler);
+
+    idt[spurious::PIC_2_SPURIOUS_VECTOR as usize]
+      .set_handler_fn(spurious::pic_2_spurious_handler);
+
+    idt[apic::SPURIOUS_VECTOR as usize]
+      .set_handler_fn(apic::spurious_interrupt_handler);
+
     idt
   };
 }
@@ -50,6 +67,20 @@ pub fn init() {
   x86_64::instructions::interrupts::enable();
 }
 
+/// Loads the shared IDT on an application processor.
+pub fn init_ap() {
+  IDT.load();
+}
+
+/// Acknowledges `index` to whichever interrupt controller delivered it.
+pub fn end_of_interrupt(index: InterruptIndex) {
+  if apic::is_enabled() {
+    apic::LocalApic::end_of_interrupt();
+  } else {
+    unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
+  }
+}
+
 pub fn without_interrupts<F, R>(f: F) -> R where F: FnOnce() -> R {
   use x86_64::instructions::interrupts::{are_enabled, disable, enable};
 
@@ -1,5 +1,5 @@
 use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
-use crate::kprintln;
+use crate::{kernel::memory::{self, stack}, kprintln};
 
 pub extern "x86-interrupt" fn page_fault_handler(
   stack_frame: InterruptStackFrame,
@@ -7,8 +7,19 @@ pub extern "x86-interrupt" fn page_fault_handler(
 ) {
   use x86_64::registers::control::Cr2;
 
+  let accessed_address = Cr2::read();
+  if memory::handle_page_fault(accessed_address, error_code) {
+    return;
+  }
+
+  if let Some(context) = stack::guard_page_owner(accessed_address) {
+    panic!("kernel stack overflow in {} at {:?}", context, stack_frame.instruction_pointer);
+  }
+
   kprintln!("EXCEPTION: PAGE FAULT");
-  kprintln!("Accessed Address: {:?}", Cr2::read());
+  kprintln!("Accessed Address: {:?}", accessed_address);
   kprintln!("Error Code: {:?}", error_code);
   kprintln!("{:#?}", stack_frame);
-}
\ No newline at end of file
+  panic!("kernel oops: invalid access to {:?} at {:?}",
+    accessed_address, stack_frame.instruction_pointer);
+}
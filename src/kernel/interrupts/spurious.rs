@@ -0,0 +1,66 @@
+// Spurious interrupts of the 8259 PICs
+//
+// When a line drops before the CPU acknowledges it, a PIC still raises its
+// lowest priority IRQ, 7 on the master and 15 on the slave, even with every
+// line masked. Such an interrupt has no bit set in the PIC's in-service
+// register and must not be acknowledged, except that a spurious IRQ 15 still
+// went through the master, which expects its EOI for the cascade line.
+//
+// The ports are accessed directly rather than through `PICS`, whose lock the
+// interrupted code may hold.
+
+use x86_64::{instructions::port::Port, structures::idt::InterruptStackFrame};
+
+use super::{PIC_1_OFFSET, PIC_2_OFFSET, apic::{self, LocalApic}};
+
+pub const PIC_1_SPURIOUS_VECTOR: u8 = PIC_1_OFFSET + 7;
+pub const PIC_2_SPURIOUS_VECTOR: u8 = PIC_2_OFFSET + 7;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xA0;
+const OCW3_READ_ISR: u8 = 0x0B;
+const PIC_EOI: u8 = 0x20;
+const IRQ_7_BIT: u8 = 1 << 7;
+
+fn in_service(command_port: u16) -> u8 {
+  let mut port: Port<u8> = Port::new(command_port);
+  unsafe {
+    port.write(OCW3_READ_ISR);
+    port.read()
+  }
+}
+
+fn end_of_interrupt(command_port: u16) {
+  unsafe { Port::<u8>::new(command_port).write(PIC_EOI) };
+}
+
+/// Acknowledges `vector` if the local APIC delivered it, which happens when
+/// the IOAPIC routes a real interrupt there. Returns whether it did.
+fn local_apic_eoi(vector: u8) -> bool {
+  if apic::is_enabled() && LocalApic::is_in_service(vector) {
+    LocalApic::end_of_interrupt();
+    return true;
+  }
+  false
+}
+
+pub extern "x86-interrupt" fn pic_1_spurious_handler(_stack_frame: InterruptStackFrame) {
+  if local_apic_eoi(PIC_1_SPURIOUS_VECTOR) {
+    return;
+  }
+  // Nothing drives IRQ 7, but a real one still needs its EOI
+  if in_service(PIC_1_COMMAND) & IRQ_7_BIT != 0 {
+    end_of_interrupt(PIC_1_COMMAND);
+  }
+}
+
+/// Also the vector of the secondary ATA channel, which has no driver yet.
+pub extern "x86-interrupt" fn pic_2_spurious_handler(_stack_frame: InterruptStackFrame) {
+  if local_apic_eoi(PIC_2_SPURIOUS_VECTOR) {
+    return;
+  }
+  if in_service(PIC_2_COMMAND) & IRQ_7_BIT != 0 {
+    end_of_interrupt(PIC_2_COMMAND);
+  }
+  end_of_interrupt(PIC_1_COMMAND);
+}
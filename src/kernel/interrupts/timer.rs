@@ -1,5 +1,5 @@
 use x86_64::structures::idt::InterruptStackFrame;
-use crate::kernel::interrupts::{InterruptIndex, PICS};
+use crate::kernel::interrupts::{self, InterruptIndex};
 use crate::kernel::time;
 
 pub extern "x86-interrupt" fn timer_interrupt_handler(
@@ -7,8 +7,5 @@ pub extern "x86-interrupt" fn timer_interrupt_handler(
 {
   time::on_timer_interrupt(stack_frame);
 
-  unsafe {
-    PICS.lock()
-      .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-  }
+  interrupts::end_of_interrupt(InterruptIndex::Timer);
 }
\ No newline at end of file
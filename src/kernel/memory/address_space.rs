@@ -0,0 +1,274 @@
+use conquer_once::spin::OnceCell;
+use x86_64::{PhysAddr, VirtAddr, instructions::tlb, registers::control::{Cr3, Cr3Flags}, structures::paging::{FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PageTableIndex, PhysFrame, Size2MiB, Size4KiB, mapper::{MapToError, UnmapError}}};
+
+use super::{cow, phys_to_virt, with_frame_allocator, frame_allocator::{BitmapFrameAllocator, FRAME_SIZE}};
+
+const ENTRY_COUNT: usize = 512;
+
+/// The level 4 table the kernel booted with, active whenever no process is.
+static KERNEL_LEVEL_4_FRAME: OnceCell<PhysFrame> = OnceCell::uninit();
+
+pub(super) fn init() {
+  KERNEL_LEVEL_4_FRAME.init_once(|| Cr3::read().0);
+}
+
+fn kernel_level_4_frame() -> PhysFrame {
+  *KERNEL_LEVEL_4_FRAME.get().expect("memory not initialized")
+}
+
+/// Switches back to the kernel's own page table.
+pub fn activate_kernel() {
+  unsafe { Cr3::write(kernel_level_4_frame(), Cr3Flags::empty()) };
+}
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+  /// The address falls in a level 4 entry shared with the kernel.
+  KernelAddress(VirtAddr),
+  OutOfMemory,
+  Map(MapToError<Size4KiB>),
+  Unmap(UnmapError),
+}
+
+/// Returns the page table stored in `frame`, through the physical memory map.
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+  &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+}
+
+/// A level 4 page table of its own for a process.
+///
+/// Every level 4 entry present in the kernel table when the space is created
+/// is shared with it, so the kernel stays mapped whichever space is active.
+/// Any other entry belongs to the process and is torn down with it.
+pub struct AddressSpace {
+  level_4_frame: PhysFrame,
+  /// Level 4 entries pointing to the kernel's tables, never freed by us.
+  kernel_entries: [bool; ENTRY_COUNT],
+}
+
+impl AddressSpace {
+  pub fn new() -> Result<Self, AddressSpaceError> {
+    let level_4_frame: PhysFrame = with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame())
+      .ok_or(AddressSpaceError::OutOfMemory)?;
+
+    let kernel_table = unsafe { table_at(kernel_level_4_frame()) };
+    let table = unsafe { table_at(level_4_frame) };
+    table.zero();
+
+    let mut kernel_entries = [false; ENTRY_COUNT];
+    for (index, entry) in kernel_table.iter().enumerate() {
+      if entry.flags().contains(PageTableFlags::PRESENT) {
+        table[index] = entry.clone();
+        kernel_entries[index] = true;
+      }
+    }
+
+    Ok(AddressSpace { level_4_frame, kernel_entries })
+  }
+
+  /// Physical frame holding the level 4 table, as loaded into CR3.
+  pub fn level_4_frame(&self) -> PhysFrame {
+    self.level_4_frame
+  }
+
+  pub fn is_active(&self) -> bool {
+    Cr3::read().0 == self.level_4_frame
+  }
+
+  /// Loads this address space into CR3.
+  pub fn activate(&self) {
+    unsafe { Cr3::write(self.level_4_frame, Cr3Flags::empty()) };
+  }
+
+  /// Whether `addr` can be mapped for the process, i.e. is not in the kernel part.
+  pub fn is_user_address(&self, addr: VirtAddr) -> bool {
+    !self.kernel_entries[usize::from(addr.p4_index())]
+  }
+
+  fn mapper(&mut self) -> OffsetPageTable<'_> {
+    unsafe { OffsetPageTable::new(table_at(self.level_4_frame), phys_to_virt(PhysAddr::new(0))) }
+  }
+
+  /// Maps `size` bytes starting at `start` to fresh zeroed frames, accessible
+  /// from user mode. Nothing stays mapped if it fails halfway.
+  pub fn map_user_range(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags)
+    -> Result<(), AddressSpaceError>
+  {
+    if size == 0 {
+      return Ok(());
+    }
+
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + size - 1u64);
+    if let Some(page) = Page::range_inclusive(first, last).find(|page| !self.is_user_address(page.start_address())) {
+      return Err(AddressSpaceError::KernelAddress(page.start_address()));
+    }
+
+    let active = self.is_active();
+    let mut mapper = self.mapper();
+    let mut mapped = 0;
+    let result = with_frame_allocator(|frame_allocator| {
+      for page in Page::range_inclusive(first, last) {
+        let frame: PhysFrame = frame_allocator.allocate_frame()
+          .ok_or(AddressSpaceError::OutOfMemory)?;
+        unsafe {
+          core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, FRAME_SIZE as usize);
+        }
+
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+          Ok(flush) if active => flush.flush(),
+          Ok(flush) => flush.ignore(),
+          Err(err) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            return Err(AddressSpaceError::Map(err));
+          },
+        }
+        mapped += 1;
+      }
+      Ok(())
+    });
+
+    if let Err(err) = result {
+      // Roll back only the pages mapped above, anything that was already
+      // mapped before the call (PageAlreadyMapped) belongs to the caller
+      for page in Page::range(first, first + mapped) {
+        self.unmap_user_page(page).ok();
+      }
+      return Err(err);
+    }
+    Ok(())
+  }
+
+  /// Unmaps a page mapped with `map_user_range` and frees its frame.
+  pub fn unmap_user_page(&mut self, page: Page) -> Result<(), AddressSpaceError> {
+    if !self.is_user_address(page.start_address()) {
+      return Err(AddressSpaceError::KernelAddress(page.start_address()));
+    }
+
+    let active = self.is_active();
+    let mut mapper = self.mapper();
+    let (frame, flush) = mapper.unmap(page).map_err(AddressSpaceError::Unmap)?;
+    if active {
+      flush.flush();
+    } else {
+      flush.ignore();
+    }
+    if cow::release_frame(frame) {
+      with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate_frame(frame) });
+    }
+    Ok(())
+  }
+
+  /// Unmaps `size` bytes starting at `start`, freeing the frames.
+  pub fn unmap_user_range(&mut self, start: VirtAddr, size: u64) -> Result<(), AddressSpaceError> {
+    if size == 0 {
+      return Ok(());
+    }
+
+    let first = Page::containing_address(start);
+    let last = Page::containing_address(start + size - 1u64);
+    for page in Page::range_inclusive(first, last) {
+      self.unmap_user_page(page)?;
+    }
+    Ok(())
+  }
+
+  /// Duplicates the address space, sharing every user frame with the copy.
+  ///
+  /// Writable pages become read-only copy-on-write pages in both spaces, so
+  /// neither sees the other's writes.
+  pub fn fork(&mut self) -> Result<AddressSpace, AddressSpaceError> {
+    let mut child = AddressSpace::new()?;
+    let kernel_entries = self.kernel_entries;
+    let level_4_table = unsafe { table_at(self.level_4_frame) };
+    let mut child_mapper = child.mapper();
+
+    let result = with_frame_allocator(|frame_allocator| {
+      for (p4, level_4_entry) in level_4_table.iter().enumerate() {
+        if kernel_entries[p4] || !level_4_entry.flags().contains(PageTableFlags::PRESENT) {
+          continue;
+        }
+        let level_3_table = unsafe { table_at(PhysFrame::containing_address(level_4_entry.addr())) };
+        for (p3, level_3_entry) in level_3_table.iter().enumerate() {
+          if !level_3_entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+          }
+          let level_2_table = unsafe { table_at(PhysFrame::containing_address(level_3_entry.addr())) };
+          for (p2, level_2_entry) in level_2_table.iter().enumerate() {
+            // map_user_range only ever creates 4 KiB pages
+            let flags = level_2_entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+              continue;
+            }
+            let level_1_table = unsafe { table_at(PhysFrame::containing_address(level_2_entry.addr())) };
+            for (p1, entry) in level_1_table.iter_mut().enumerate() {
+              if !entry.flags().contains(PageTableFlags::PRESENT) {
+                continue;
+              }
+
+              let page = Page::from_page_table_indices(
+                PageTableIndex::new(p4 as u16), PageTableIndex::new(p3 as u16),
+                PageTableIndex::new(p2 as u16), PageTableIndex::new(p1 as u16),
+              );
+              let frame = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+              let flags = cow::shared_flags(entry.flags());
+              entry.set_flags(flags);
+
+              let flush = unsafe { child_mapper.map_to(page, frame, flags, frame_allocator) }
+                .map_err(AddressSpaceError::Map)?;
+              flush.ignore();
+              cow::share_frame(frame);
+            }
+          }
+        }
+      }
+      Ok(())
+    });
+
+    // Our writable pages just became read-only
+    if self.is_active() {
+      tlb::flush_all();
+    }
+    result.map(|()| child)
+  }
+}
+
+/// Frees every frame reachable from the page table in `frame`, which sits at
+/// `level` of the hierarchy, and the table itself.
+unsafe fn free_table(frame: PhysFrame, level: u8, frame_allocator: &mut BitmapFrameAllocator) {
+  for entry in table_at(frame).iter() {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+      continue;
+    }
+
+    if level == 1 {
+      let frame = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+      if cow::release_frame(frame) {
+        frame_allocator.deallocate_frame(frame);
+      }
+    } else if level == 2 && flags.contains(PageTableFlags::HUGE_PAGE) {
+      frame_allocator.deallocate_frame(PhysFrame::<Size2MiB>::containing_address(entry.addr()));
+    } else if !flags.contains(PageTableFlags::HUGE_PAGE) {
+      free_table(PhysFrame::containing_address(entry.addr()), level - 1, frame_allocator);
+    }
+  }
+  frame_allocator.deallocate_frame(frame);
+}
+
+impl Drop for AddressSpace {
+  fn drop(&mut self) {
+    assert!(!self.is_active(), "dropping the active address space");
+
+    let table = unsafe { table_at(self.level_4_frame) };
+    with_frame_allocator(|frame_allocator| unsafe {
+      for (index, entry) in table.iter().enumerate() {
+        if !self.kernel_entries[index] && entry.flags().contains(PageTableFlags::PRESENT) {
+          free_table(PhysFrame::containing_address(entry.addr()), 3, frame_allocator);
+        }
+      }
+      frame_allocator.deallocate_frame(self.level_4_frame);
+    });
+  }
+}
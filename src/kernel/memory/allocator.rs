@@ -1,49 +0,0 @@
-// TODO: Add Fixed Block Allocations 
-// https://os.phil-opp.com/allocator-designs/
-
-use x86_64::{
-  structures::paging::{
-      mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-  },
-  VirtAddr,
-};
-use linked_list_allocator::LockedHeap;
-
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
-
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
-
-pub fn init_heap(
-  mapper: &mut impl Mapper<Size4KiB>,
-  frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-  // Get the page properties before creating the physical frame
-  let page_range = {
-    let heap_start = VirtAddr::new(HEAP_START as u64);
-    let heap_end = heap_start + HEAP_SIZE - 1u64;
-    let heap_start_page = Page::containing_address(heap_start);
-    let heap_end_page = Page::containing_address(heap_end);
-    Page::range_inclusive(heap_start_page, heap_end_page)
-  };
-
-  // For every page in the heap
-  for page in page_range {
-    // Allocate physical frames as present and writable
-    let frame = frame_allocator
-      .allocate_frame()
-      .ok_or(MapToError::FrameAllocationFailed)?;
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-    unsafe {
-      // Map frame to the current page table
-      mapper.map_to(page, frame, flags, frame_allocator)?.flush()
-    };
-  }
-
-  unsafe {
-    ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
-  }
-
-  Ok(())
-}
@@ -0,0 +1,188 @@
+// Heap debugging wrapper, enabled with the `heap-debug` cargo feature.
+//
+// Every allocation gets a redzone on both sides, is recorded in a fixed-size
+// table together with the return addresses of its callers, and is poisoned
+// when freed. Frees check the redzones and the table to catch overflows,
+// double frees and frees of pointers that never came from the heap.
+
+use core::{alloc::{GlobalAlloc, Layout}, ptr, slice};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use super::ALLOCATOR;
+use crate::kernel::memory::stack;
+
+const REDZONE_SIZE: usize = 16;
+const REDZONE_BYTE: u8 = 0xFD;
+const POISON_BYTE: u8 = 0xDD;
+
+const MAX_TRACKED_ALLOCATIONS: usize = 1024;
+pub const CALLER_DEPTH: usize = 8;
+
+/// Frames between `DebugAllocator::alloc` and the allocating code that are
+/// always the same: the `#[global_allocator]` shim and `alloc::alloc::alloc`.
+const SKIPPED_FRAMES: usize = 2;
+
+/// A live allocation and the return addresses leading to it.
+#[derive(Debug, Clone, Copy)]
+pub struct AllocationRecord {
+  pub ptr: usize,
+  pub size: usize,
+  pub align: usize,
+  pub callers: [usize; CALLER_DEPTH],
+}
+
+struct AllocationTable {
+  records: [Option<AllocationRecord>; MAX_TRACKED_ALLOCATIONS],
+  /// Allocations that could not be recorded because the table was full.
+  untracked: usize,
+}
+
+impl AllocationTable {
+  const fn new() -> Self {
+    AllocationTable {
+      records: [None; MAX_TRACKED_ALLOCATIONS],
+      untracked: 0,
+    }
+  }
+
+  fn insert(&mut self, record: AllocationRecord) {
+    match self.records.iter_mut().find(|slot| slot.is_none()) {
+      Some(slot) => *slot = Some(record),
+      None => self.untracked += 1,
+    }
+  }
+
+  fn remove(&mut self, ptr: usize) -> Option<AllocationRecord> {
+    self.records.iter_mut()
+      .find(|slot| matches!(slot, Some(record) if record.ptr == ptr))
+      .and_then(|slot| slot.take())
+  }
+}
+
+static ALLOCATION_TABLE: Mutex<AllocationTable> = Mutex::new(AllocationTable::new());
+
+/// Calls `f` for every live allocation recorded by the debug allocator and
+/// returns how many allocations could not be recorded.
+///
+/// `f` must not allocate, as the allocation table is locked meanwhile.
+pub fn for_each_allocation<F>(mut f: F) -> usize where F: FnMut(&AllocationRecord) {
+  let table = ALLOCATION_TABLE.lock();
+  for record in table.records.iter().flatten() {
+    f(record);
+  }
+  table.untracked
+}
+
+/// Wraps the fixed-size block allocator with redzones and bookkeeping.
+pub struct DebugAllocator;
+
+/// Space in front of the user pointer: a redzone, rounded up so the user
+/// pointer keeps the requested alignment.
+fn front_size(layout: &Layout) -> usize {
+  REDZONE_SIZE.max(layout.align())
+}
+
+fn outer_layout(layout: &Layout) -> Layout {
+  let size = front_size(layout) + layout.size() + REDZONE_SIZE;
+  Layout::from_size_align(size, layout.align()).unwrap()
+}
+
+unsafe impl GlobalAlloc for DebugAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    let base = ALLOCATOR.alloc(outer_layout(&layout));
+    if base.is_null() {
+      return base;
+    }
+
+    let front = front_size(&layout);
+    let user = base.add(front);
+    ptr::write_bytes(base, REDZONE_BYTE, front);
+    ptr::write_bytes(user.add(layout.size()), REDZONE_BYTE, REDZONE_SIZE);
+
+    ALLOCATION_TABLE.lock().insert(AllocationRecord {
+      ptr: user as usize,
+      size: layout.size(),
+      align: layout.align(),
+      callers: caller_addresses(),
+    });
+
+    user
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    let front = front_size(&layout);
+    let base = ptr.sub(front);
+    let front_redzone = slice::from_raw_parts(base, front);
+    let back_redzone = slice::from_raw_parts(ptr.add(layout.size()), REDZONE_SIZE);
+
+    let (record, untracked) = {
+      let mut table = ALLOCATION_TABLE.lock();
+      (table.remove(ptr as usize), table.untracked)
+    };
+
+    if record.is_none() {
+      // The allocator reuses the start of freed blocks for its free lists,
+      // so look for the poison in the user part instead of the redzone
+      let user_start = slice::from_raw_parts(ptr, layout.size().min(REDZONE_SIZE));
+      if !user_start.is_empty() && user_start.iter().all(|&b| b == POISON_BYTE) {
+        panic!("heap-debug: double free of {:p} ({} bytes)", ptr, layout.size());
+      }
+      if untracked == 0 {
+        panic!("heap-debug: free of {:p} which was never allocated", ptr);
+      }
+    }
+
+    if front_redzone.iter().any(|&b| b != REDZONE_BYTE) {
+      panic!("heap-debug: underflow before {:p} ({} bytes, allocated from {:x?})",
+        ptr, layout.size(), record.map(|r| r.callers));
+    }
+    if back_redzone.iter().any(|&b| b != REDZONE_BYTE) {
+      panic!("heap-debug: overflow after {:p} ({} bytes, allocated from {:x?})",
+        ptr, layout.size(), record.map(|r| r.callers));
+    }
+
+    let outer = outer_layout(&layout);
+    ptr::write_bytes(base, POISON_BYTE, outer.size());
+    ALLOCATOR.dealloc(base, outer);
+  }
+}
+
+/// Walks the frame pointer chain to find who asked for the allocation.
+///
+/// The allocator plumbing is skipped, but collections add a few frames of
+/// their own (`RawVec`, `Box::new`...) before the real caller, hence the
+/// depth. Relies on the kernel being built with frame pointers, which the
+/// build script enforces. Only frames on the current kernel stack are
+/// followed, the outermost saved `rbp` is whatever the bootloader left there.
+/// Slots past the end of the chain are left zero.
+#[inline(always)]
+fn caller_addresses() -> [usize; CALLER_DEPTH] {
+  let mut callers = [0; CALLER_DEPTH];
+  let mut frame: usize;
+  let rsp: u64;
+  unsafe { asm!("mov {}, rbp", "mov {}, rsp", out(reg) frame, out(reg) rsp) };
+
+  // Allocations made before the stack is registered record no callers
+  let top = match stack::stack_bounds(VirtAddr::new(rsp)) {
+    Some((_, top)) => top.as_u64() as usize,
+    None => return callers,
+  };
+
+  for depth in 0..SKIPPED_FRAMES + CALLER_DEPTH {
+    // A frame holds the saved rbp and the return address
+    if frame < rsp as usize || frame > top - 16 || frame % 8 != 0 {
+      break;
+    }
+    let next = unsafe { *(frame as *const usize) };
+    if depth >= SKIPPED_FRAMES {
+      callers[depth - SKIPPED_FRAMES] = unsafe { *((frame + 8) as *const usize) };
+    }
+    // Stacks grow down, so callers' frames are always at higher addresses
+    if next <= frame {
+      break;
+    }
+    frame = next;
+  }
+  callers
+}
@@ -0,0 +1,224 @@
+use core::{alloc::{GlobalAlloc, Layout}, mem, ptr::{self, NonNull}};
+use linked_list_allocator::Heap;
+
+use super::Locked;
+
+/// The block sizes to use.
+///
+/// The sizes must each be power of 2 because they are also used as
+/// the block alignment (alignments must be always powers of 2).
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Allocation counters of a single block size class.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockClassStats {
+  pub block_size: usize,
+  pub allocations: usize,
+  pub deallocations: usize,
+  pub in_use: usize,
+  pub free_blocks: usize,
+}
+
+/// Allocation counters of the linked list heap used for large layouts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FallbackStats {
+  pub allocations: usize,
+  pub deallocations: usize,
+  pub used: usize,
+  pub free: usize,
+  pub size: usize,
+}
+
+struct ListNode {
+  next: Option<&'static mut ListNode>,
+}
+
+#[derive(Clone, Copy)]
+struct ClassCounters {
+  allocations: usize,
+  deallocations: usize,
+  free_blocks: usize,
+}
+
+impl ClassCounters {
+  const fn new() -> Self {
+    ClassCounters { allocations: 0, deallocations: 0, free_blocks: 0 }
+  }
+}
+
+/// Serves small layouts from per-size free lists and hands everything
+/// bigger than the largest block size to a linked list heap.
+pub struct FixedSizeBlockAllocator {
+  list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+  counters: [ClassCounters; BLOCK_SIZES.len()],
+  fallback_allocator: Heap,
+  fallback_allocations: usize,
+  fallback_deallocations: usize,
+}
+
+impl FixedSizeBlockAllocator {
+  /// Creates an empty FixedSizeBlockAllocator.
+  pub const fn new() -> Self {
+    const EMPTY: Option<&'static mut ListNode> = None;
+    FixedSizeBlockAllocator {
+      list_heads: [EMPTY; BLOCK_SIZES.len()],
+      counters: [ClassCounters::new(); BLOCK_SIZES.len()],
+      fallback_allocator: Heap::empty(),
+      fallback_allocations: 0,
+      fallback_deallocations: 0,
+    }
+  }
+
+  /// Initialize the allocator with the given heap bounds.
+  ///
+  /// This function is unsafe because the caller must guarantee that the given
+  /// heap bounds are valid and that the heap is unused. This method must be
+  /// called only once.
+  pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+    self.fallback_allocator.init(heap_start, heap_size);
+  }
+
+  /// Returns the counters of every block size class.
+  pub fn class_stats(&self) -> [BlockClassStats; BLOCK_SIZES.len()] {
+    let mut stats = [BlockClassStats::default(); BLOCK_SIZES.len()];
+    for (index, class) in stats.iter_mut().enumerate() {
+      let counters = self.counters[index];
+      *class = BlockClassStats {
+        block_size: BLOCK_SIZES[index],
+        allocations: counters.allocations,
+        deallocations: counters.deallocations,
+        in_use: counters.allocations - counters.deallocations,
+        free_blocks: counters.free_blocks,
+      };
+    }
+    stats
+  }
+
+  /// Returns the counters of the fallback heap.
+  pub fn fallback_stats(&self) -> FallbackStats {
+    FallbackStats {
+      allocations: self.fallback_allocations,
+      deallocations: self.fallback_deallocations,
+      used: self.fallback_allocator.used(),
+      free: self.fallback_allocator.free(),
+      size: self.fallback_allocator.size(),
+    }
+  }
+
+  /// Allocates from a free list, or from the fallback allocator for layouts
+  /// too big for any block size.
+  fn allocate(&mut self, layout: Layout) -> *mut u8 {
+    match list_index(&layout) {
+      Some(index) => {
+        let ptr = match self.list_heads[index].take() {
+          Some(node) => {
+            self.list_heads[index] = node.next.take();
+            self.counters[index].free_blocks -= 1;
+            node as *mut ListNode as *mut u8
+          }
+          None => {
+            // No block exists in list => allocate new block
+            let block_size = BLOCK_SIZES[index];
+            // Only works if all block sizes are a power of 2
+            let block_align = block_size;
+            let layout = Layout::from_size_align(block_size, block_align).unwrap();
+            self.fallback_alloc(layout)
+          }
+        };
+        if !ptr.is_null() {
+          self.counters[index].allocations += 1;
+        }
+        ptr
+      }
+      None => {
+        let ptr = self.fallback_alloc(layout);
+        if !ptr.is_null() {
+          self.fallback_allocations += 1;
+        }
+        ptr
+      }
+    }
+  }
+
+  /// Allocates using the fallback allocator, growing the heap if needed.
+  fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+    if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+      return ptr.as_ptr();
+    }
+    self.grow_and_alloc(layout).unwrap_or(ptr::null_mut())
+  }
+
+  fn grow_and_alloc(&mut self, layout: Layout) -> Option<*mut u8> {
+    let added = super::grow_heap(self.fallback_allocator.top(), &layout)?;
+    unsafe { self.fallback_allocator.extend(added) };
+    self.fallback_allocator.allocate_first_fit(layout)
+      .ok()
+      .map(|ptr| ptr.as_ptr())
+  }
+
+  /// Hands every block sitting in the free lists back to the fallback heap
+  /// so they can be merged into bigger holes, and returns how many bytes that
+  /// was.
+  pub fn release_cached_blocks(&mut self) -> usize {
+    let mut released = 0;
+    for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+      let layout = Layout::from_size_align(block_size, block_size).unwrap();
+      while let Some(node) = self.list_heads[index].take() {
+        self.list_heads[index] = node.next.take();
+        let ptr = NonNull::from(node).cast::<u8>();
+        unsafe { self.fallback_allocator.deallocate(ptr, layout) };
+        released += block_size;
+      }
+      self.counters[index].free_blocks = 0;
+    }
+    released
+  }
+}
+
+/// Choose an appropriate block size for the given layout.
+///
+/// Returns an index into the `BLOCK_SIZES` array.
+fn list_index(layout: &Layout) -> Option<usize> {
+  let required_block_size = layout.size().max(layout.align());
+  BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    let ptr = self.lock().allocate(layout);
+    if !ptr.is_null() {
+      return ptr;
+    }
+
+    // The heap can't grow any more. The reclaimers run without the lock so
+    // that they may use the allocator themselves, then try once more.
+    if super::reclaim_memory() > 0 {
+      return self.lock().allocate(layout);
+    }
+    ptr
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    let mut allocator = self.lock();
+    match list_index(&layout) {
+      Some(index) => {
+        let new_node = ListNode {
+          next: allocator.list_heads[index].take(),
+        };
+        // Verify that block has size and alignment required for storing node
+        assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+        assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+        let new_node_ptr = ptr as *mut ListNode;
+        new_node_ptr.write(new_node);
+        allocator.list_heads[index] = Some(&mut *new_node_ptr);
+        allocator.counters[index].deallocations += 1;
+        allocator.counters[index].free_blocks += 1;
+      }
+      None => {
+        let ptr = NonNull::new(ptr).unwrap();
+        allocator.fallback_allocator.deallocate(ptr, layout);
+        allocator.fallback_deallocations += 1;
+      }
+    }
+  }
+}
@@ -0,0 +1,185 @@
+// Fixed Block Allocations based on
+// https://os.phil-opp.com/allocator-designs/
+
+use core::{alloc::Layout, sync::atomic::{AtomicBool, AtomicUsize, Ordering}};
+use x86_64::{
+  structures::paging::{
+      mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+  },
+  VirtAddr,
+};
+
+use fixed_size_block::{BlockClassStats, FallbackStats, FixedSizeBlockAllocator, BLOCK_SIZES};
+
+pub mod fixed_size_block;
+#[cfg(feature = "heap-debug")]
+pub mod debug;
+
+#[cfg_attr(not(feature = "heap-debug"), global_allocator)]
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+
+#[cfg(feature = "heap-debug")]
+#[global_allocator]
+static DEBUG_ALLOCATOR: debug::DebugAllocator = debug::DebugAllocator;
+
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+
+/// Minimum amount of memory mapped every time the heap grows.
+const HEAP_GROWTH_STEP: usize = 64 * 1024; // 64 KiB
+const PAGE_SIZE: usize = 4096;
+
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+static HEAP_LIMIT_REACHED: AtomicBool = AtomicBool::new(false);
+
+/// A wrapper around spin::Mutex to permit trait implementations.
+pub struct Locked<A> {
+  inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+  pub const fn new(inner: A) -> Self {
+    Locked {
+      inner: spin::Mutex::new(inner),
+    }
+  }
+
+  pub fn lock(&self) -> spin::MutexGuard<A> {
+    self.inner.lock()
+  }
+}
+
+pub fn init_heap(
+  mapper: &mut impl Mapper<Size4KiB>,
+  frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+  // Get the page properties before creating the physical frame
+  let page_range = {
+    let heap_start = VirtAddr::new(HEAP_START as u64);
+    let heap_end = heap_start + HEAP_SIZE - 1u64;
+    let heap_start_page = Page::containing_address(heap_start);
+    let heap_end_page = Page::containing_address(heap_end);
+    Page::range_inclusive(heap_start_page, heap_end_page)
+  };
+
+  // For every page in the heap
+  for page in page_range {
+    // Allocate physical frames as present and writable
+    let frame = frame_allocator
+      .allocate_frame()
+      .ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+      // Map frame to the current page table
+      mapper.map_to(page, frame, flags, frame_allocator)?.flush()
+    };
+  }
+
+  unsafe {
+    ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+  }
+
+  Ok(())
+}
+
+/// Sets the maximum size the heap is allowed to grow to.
+///
+/// Limits smaller than the currently mapped heap only prevent further growth.
+pub fn set_heap_limit(limit: usize) {
+  HEAP_LIMIT.store(limit, Ordering::Relaxed);
+  HEAP_LIMIT_REACHED.store(false, Ordering::Relaxed);
+}
+
+/// Maximum size the heap is allowed to grow to.
+pub fn heap_limit() -> usize {
+  HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Whether an allocation was refused because growing the heap would have
+/// crossed its limit.
+pub fn heap_limit_reached() -> bool {
+  HEAP_LIMIT_REACHED.load(Ordering::Relaxed)
+}
+
+/// Maps more memory right after `heap_top` so that `layout` fits.
+///
+/// Returns the amount of bytes mapped, or `None` if the heap can't grow.
+/// Called with the allocator locked, so it must not allocate.
+fn grow_heap(heap_top: usize, layout: &Layout) -> Option<usize> {
+  let current_size = heap_top - HEAP_START;
+  let limit = heap_limit();
+
+  // Worst case the allocation needs padding to satisfy its alignment
+  let required = align_up(layout.size() + layout.align(), PAGE_SIZE);
+  if current_size + required > limit {
+    HEAP_LIMIT_REACHED.store(true, Ordering::Relaxed);
+    return None;
+  }
+  let wanted = core::cmp::min(required.max(HEAP_GROWTH_STEP), limit - current_size);
+
+  // Fall back to the bare minimum when memory is too tight for a full step
+  let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+  let heap_top = VirtAddr::new(heap_top as u64);
+  [wanted, required].iter()
+    .find(|&&size| super::map_range(heap_top, size as u64, flags).is_ok())
+    .copied()
+}
+
+/// Asks the rest of the kernel to give memory back, returning the amount of
+/// frames freed. Called without the allocator locked.
+fn reclaim_memory() -> usize {
+  super::oom::reclaim()
+}
+
+/// Gives the blocks cached in the free lists back to the heap, so they can
+/// serve bigger layouts. Registered as an OOM reclaimer.
+pub fn release_cached_blocks() -> usize {
+  let released = ALLOCATOR.lock().release_cached_blocks();
+  (released + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+  (value + align - 1) / align * align
+}
+
+/// Usage of the kernel heap, in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+  /// Currently mapped heap size.
+  pub size: usize,
+  /// Size the heap is allowed to grow to.
+  pub limit: usize,
+  /// Bytes handed out to callers.
+  pub used: usize,
+  /// Bytes available without growing the heap, including cached blocks.
+  pub free: usize,
+}
+
+/// Returns the current usage of the kernel heap.
+pub fn heap_stats() -> HeapStats {
+  let (fallback, classes) = {
+    let allocator = ALLOCATOR.lock();
+    (allocator.fallback_stats(), allocator.class_stats())
+  };
+  // Blocks sitting in the free lists are used as far as the fallback heap
+  // is concerned, but are free for callers
+  let cached: usize = classes.iter().map(|class| class.free_blocks * class.block_size).sum();
+
+  HeapStats {
+    size: fallback.size,
+    limit: heap_limit(),
+    used: fallback.used - cached,
+    free: fallback.free + cached,
+  }
+}
+
+/// Returns the allocation counters of every block size class.
+pub fn block_stats() -> [BlockClassStats; BLOCK_SIZES.len()] {
+  ALLOCATOR.lock().class_stats()
+}
+
+/// Returns the allocation counters of the heap serving large layouts.
+pub fn fallback_stats() -> FallbackStats {
+  ALLOCATOR.lock().fallback_stats()
+}
@@ -0,0 +1,129 @@
+// Copy-on-write sharing of user frames.
+//
+// A frame mapped by more than one address space has a share count, the amount
+// of extra mappings it has. Writable pages are shared read-only with the
+// `COW` bit set, and the first write to one copies the frame, unless every
+// other mapping went away in the meantime.
+
+use core::{mem, slice, sync::atomic::{AtomicU16, Ordering}};
+use conquer_once::spin::OnceCell;
+use x86_64::{PhysAddr, VirtAddr, registers::control::{Cr0, Cr0Flags, Cr3}, structures::paging::{FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate, mapper::{MappedFrame, TranslateResult}}};
+
+use super::{phys_to_virt, try_with_memory_manager, with_frame_allocator, frame_allocator::FRAME_SIZE};
+
+/// Marks a page that was writable before being shared.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Extra mappings per frame number, zero for a frame with a single owner.
+///
+/// Lives in frames of its own rather than on the heap so the page fault
+/// handler can use it whatever locks the faulting code holds.
+static SHARE_COUNTS: OnceCell<&'static [AtomicU16]> = OnceCell::uninit();
+
+pub(super) fn init() {
+  let frame_count = with_frame_allocator(|frame_allocator| frame_allocator.frame_count());
+  let bytes = (frame_count * mem::size_of::<AtomicU16>()) as u64;
+  let frames = ((bytes + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+
+  let start = with_frame_allocator(|frame_allocator| frame_allocator.allocate_contiguous(frames))
+    .expect("no memory left for the frame share counts");
+  let counts = unsafe {
+    let ptr: *mut AtomicU16 = phys_to_virt(start.start_address()).as_mut_ptr();
+    core::ptr::write_bytes(ptr, 0, frame_count);
+    slice::from_raw_parts(ptr, frame_count)
+  };
+  SHARE_COUNTS.init_once(|| counts);
+
+  // Without it the kernel would write straight through read-only pages
+  unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+}
+
+fn share_count(frame: PhysFrame) -> &'static AtomicU16 {
+  let counts = SHARE_COUNTS.get().expect("copy-on-write not initialized");
+  &counts[(frame.start_address().as_u64() / FRAME_SIZE) as usize]
+}
+
+/// Records one more mapping of `frame`.
+pub fn share_frame(frame: PhysFrame) {
+  share_count(frame).fetch_add(1, Ordering::AcqRel);
+}
+
+/// Drops one mapping of `frame`. Returns `true` if it was the last one, in
+/// which case the caller must free the frame.
+pub fn release_frame(frame: PhysFrame) -> bool {
+  share_count(frame)
+    .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| count.checked_sub(1))
+    .is_err()
+}
+
+/// Returns whether more than one mapping of `frame` exists.
+pub fn is_shared(frame: PhysFrame) -> bool {
+  share_count(frame).load(Ordering::Acquire) > 0
+}
+
+/// Turns a shared mapping's flags into the ones used for both copies.
+pub fn shared_flags(flags: PageTableFlags) -> PageTableFlags {
+  if flags.contains(PageTableFlags::WRITABLE) {
+    (flags - PageTableFlags::WRITABLE) | COW
+  } else {
+    flags
+  }
+}
+
+/// Tries to resolve a write fault on a copy-on-write page of the active
+/// address space. Returns `true` if the write can be restarted.
+pub(super) fn handle_cow_fault(addr: VirtAddr) -> bool {
+  let page = Page::<Size4KiB>::containing_address(addr);
+
+  try_with_memory_manager(|memory_manager| {
+    let level_4_table = phys_to_virt(Cr3::read().0.start_address()).as_mut_ptr::<PageTable>();
+    let mut mapper = unsafe { OffsetPageTable::new(&mut *level_4_table, phys_to_virt(PhysAddr::new(0))) };
+
+    let (frame, flags) = match mapper.translate(addr) {
+      TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+      _ => return false,
+    };
+    if !flags.contains(COW) {
+      return false;
+    }
+    let flags = (flags - COW) | PageTableFlags::WRITABLE;
+
+    // Every other mapping is gone, the page can simply be made writable again
+    if !is_shared(frame) {
+      return match unsafe { mapper.update_flags(page, flags) } {
+        Ok(flush) => { flush.flush(); true },
+        Err(_) => false,
+      };
+    }
+
+    let frame_allocator = memory_manager.frame_allocator();
+    let copy: PhysFrame = match frame_allocator.allocate_frame() {
+      Some(copy) => copy,
+      None => return false,
+    };
+    unsafe {
+      core::ptr::copy_nonoverlapping(
+        phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+        phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+        FRAME_SIZE as usize,
+      );
+    }
+
+    match mapper.unmap(page) {
+      Ok((_, flush)) => flush.flush(),
+      Err(_) => {
+        unsafe { frame_allocator.deallocate_frame(copy) };
+        return false;
+      },
+    }
+    match unsafe { mapper.map_to(page, copy, flags, frame_allocator) } {
+      Ok(flush) => flush.flush(),
+      Err(_) => panic!("failed to remap copy-on-write page {:?}", page),
+    }
+
+    if release_frame(frame) {
+      unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+    true
+  }).unwrap_or(false)
+}
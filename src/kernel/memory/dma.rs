@@ -0,0 +1,134 @@
+use core::slice;
+use x86_64::{PhysAddr, VirtAddr, structures::paging::PhysFrame};
+
+use super::{phys_to_virt, with_frame_allocator, frame_allocator::FRAME_SIZE};
+
+/// Placement requirements of a DMA buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct DmaConstraints {
+  /// Alignment of the buffer's physical address, in bytes.
+  pub align: usize,
+  /// The buffer must not cross a multiple of this many bytes.
+  pub boundary: Option<usize>,
+  /// The whole buffer must lie below this physical address.
+  pub max_addr: u64,
+}
+
+impl DmaConstraints {
+  /// Constraints of 32-bit bus masters: page aligned and below 4 GiB.
+  pub const DEFAULT: DmaConstraints = DmaConstraints {
+    align: FRAME_SIZE as usize,
+    boundary: None,
+    max_addr: 0x1_0000_0000,
+  };
+
+  /// Constraints of the ISA DMA controller: below 16 MiB and never crossing
+  /// a 64 KiB boundary.
+  pub const ISA: DmaConstraints = DmaConstraints {
+    align: FRAME_SIZE as usize,
+    boundary: Some(64 * 1024),
+    max_addr: 0x100_0000,
+  };
+}
+
+impl Default for DmaConstraints {
+  fn default() -> Self {
+    DmaConstraints::DEFAULT
+  }
+}
+
+#[derive(Debug)]
+pub enum DmaError {
+  /// Zero bytes were asked for.
+  EmptyBuffer,
+  /// The alignment is not a power of two.
+  InvalidAlignment(usize),
+  /// The boundary is smaller than a frame or not a power of two.
+  InvalidBoundary(usize),
+  /// No run of free frames satisfies the constraints.
+  OutOfMemory,
+}
+
+/// A zeroed, physically contiguous buffer a device can access directly.
+///
+/// The frames are given back to the frame allocator when the buffer is dropped.
+#[derive(Debug)]
+pub struct DmaBuffer {
+  phys: PhysAddr,
+  virt: VirtAddr,
+  size: usize,
+  frames: usize,
+}
+
+/// Allocates a DMA buffer of at least `size` bytes below 4 GiB.
+pub fn alloc(size: usize) -> Result<DmaBuffer, DmaError> {
+  alloc_constrained(size, DmaConstraints::DEFAULT)
+}
+
+/// Allocates a DMA buffer of at least `size` bytes satisfying `constraints`.
+pub fn alloc_constrained(size: usize, constraints: DmaConstraints) -> Result<DmaBuffer, DmaError> {
+  let frame_size = FRAME_SIZE as usize;
+  if size == 0 {
+    return Err(DmaError::EmptyBuffer);
+  }
+  if !constraints.align.is_power_of_two() {
+    return Err(DmaError::InvalidAlignment(constraints.align));
+  }
+  // Boundaries are enforced in whole frames
+  let boundary = match constraints.boundary {
+    Some(boundary) if boundary < frame_size || !boundary.is_power_of_two() => {
+      return Err(DmaError::InvalidBoundary(boundary));
+    },
+    Some(boundary) => boundary / frame_size,
+    None => 0,
+  };
+
+  let frames = (size + frame_size - 1) / frame_size;
+  let align = core::cmp::max(constraints.align / frame_size, 1);
+  let limit = (constraints.max_addr / FRAME_SIZE) as usize;
+
+  let frame = with_frame_allocator(|frame_allocator| {
+    frame_allocator.allocate_contiguous_constrained(frames, align, boundary, limit)
+  }).ok_or(DmaError::OutOfMemory)?;
+
+  let phys = frame.start_address();
+  let virt = phys_to_virt(phys);
+  unsafe {
+    core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, frames * frame_size);
+  }
+
+  Ok(DmaBuffer { phys, virt, size, frames })
+}
+
+impl DmaBuffer {
+  /// Physical address to program into the device.
+  pub fn phys_addr(&self) -> PhysAddr {
+    self.phys
+  }
+
+  /// Kernel virtual address of the buffer.
+  pub fn virt_addr(&self) -> VirtAddr {
+    self.virt
+  }
+
+  pub fn size(&self) -> usize {
+    self.size
+  }
+
+  pub fn as_slice(&self) -> &[u8] {
+    unsafe { slice::from_raw_parts(self.virt.as_ptr(), self.size) }
+  }
+
+  pub fn as_mut_slice(&mut self) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.size) }
+  }
+}
+
+impl Drop for DmaBuffer {
+  fn drop(&mut self) {
+    let frame = PhysFrame::containing_address(self.phys);
+    with_frame_allocator(|frame_allocator| unsafe {
+      frame_allocator.deallocate_contiguous(frame, self.frames);
+    });
+  }
+}
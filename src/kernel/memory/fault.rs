@@ -0,0 +1,115 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{VirtAddr, structures::{idt::PageFaultErrorCode, paging::{FrameAllocator, FrameDeallocator, Page, PageTableFlags, PhysFrame}}};
+
+use super::{cow, phys_to_virt, try_with_memory_manager, with_memory_manager, frame_allocator::FRAME_SIZE};
+
+/// A virtual range whose pages only get a frame on first access.
+#[derive(Debug, Clone, Copy)]
+struct LazyRegion {
+  start: VirtAddr,
+  end: VirtAddr,
+  flags: PageTableFlags,
+}
+
+impl LazyRegion {
+  fn contains(&self, addr: VirtAddr) -> bool {
+    addr >= self.start && addr < self.end
+  }
+}
+
+static LAZY_REGIONS: Mutex<Vec<LazyRegion>> = Mutex::new(Vec::new());
+
+/// Registers `size` bytes starting at `start` as lazily backed.
+///
+/// Nothing is mapped yet: each page gets a zeroed frame mapped with `flags`
+/// the first time it is touched.
+pub fn register_lazy_region(start: VirtAddr, size: u64, flags: PageTableFlags) {
+  let region = LazyRegion {
+    start,
+    end: start + size,
+    flags: flags | PageTableFlags::PRESENT,
+  };
+
+  let mut regions = LAZY_REGIONS.lock();
+  assert!(
+    regions.iter().all(|r| region.end <= r.start || region.start >= r.end),
+    "lazy region {:?}-{:?} overlaps an existing one", region.start, region.end
+  );
+  regions.push(region);
+}
+
+/// Removes the lazy region starting at `start`, unmapping and freeing every
+/// page that was touched. Returns `false` if no such region exists.
+pub fn unregister_lazy_region(start: VirtAddr) -> bool {
+  let region = {
+    let mut regions = LAZY_REGIONS.lock();
+    match regions.iter().position(|r| r.start == start) {
+      Some(index) => regions.remove(index),
+      None => return false,
+    }
+  };
+
+  let first = Page::containing_address(region.start);
+  let last = Page::containing_address(region.end - 1u64);
+  with_memory_manager(|memory_manager| {
+    for page in Page::range_inclusive(first, last) {
+      unsafe { memory_manager.unmap_and_free_page(page).ok() };
+    }
+  });
+  true
+}
+
+/// Tries to resolve a page fault at `addr`.
+///
+/// Returns `true` if a frame was mapped and the faulting instruction can be
+/// restarted, `false` if the access is genuinely invalid.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+  // Faults on present pages are permission problems, not missing backing,
+  // unless the page is shared copy-on-write
+  if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+    return error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && cow::handle_cow_fault(addr);
+  }
+
+  // The faulting code may hold the lock, in which case this is not ours to fix
+  let region = match LAZY_REGIONS.try_lock() {
+    Some(regions) => regions.iter().find(|r| r.contains(addr)).copied(),
+    None => None,
+  };
+  let region = match region {
+    Some(region) => region,
+    None => return false,
+  };
+
+  if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+    && !region.flags.contains(PageTableFlags::WRITABLE)
+  {
+    return false;
+  }
+  if error_code.contains(PageFaultErrorCode::USER_MODE)
+    && !region.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+  {
+    return false;
+  }
+
+  let page = Page::containing_address(addr);
+  try_with_memory_manager(|memory_manager| {
+    let frame: PhysFrame = match memory_manager.frame_allocator().allocate_frame() {
+      Some(frame) => frame,
+      None => return false,
+    };
+
+    // Zero through the physical memory map before anyone can see the page
+    unsafe {
+      core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, FRAME_SIZE as usize);
+    }
+
+    match unsafe { memory_manager.map_to(page, frame, region.flags) } {
+      Ok(()) => true,
+      Err(_) => {
+        unsafe { memory_manager.frame_allocator().deallocate_frame(frame) };
+        false
+      },
+    }
+  }).unwrap_or(false)
+}
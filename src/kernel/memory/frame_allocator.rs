@@ -0,0 +1,285 @@
+use core::slice;
+use x86_64::{PhysAddr, structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB}};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+
+use super::phys_to_virt;
+
+pub const FRAME_SIZE: u64 = 4096;
+
+/// Amount of 4 KiB frames making up a 2 MiB frame.
+const FRAMES_PER_HUGE_FRAME: usize = (Size2MiB::SIZE / FRAME_SIZE) as usize;
+
+const BITS_PER_WORD: usize = 64;
+
+/// A physical frame allocator keeping one bit per 4 KiB frame.
+///
+/// A set bit means the frame is in use (or not RAM at all), a clear bit means
+/// it can be handed out. The bitmap itself lives in the first usable region
+/// large enough to hold it and is accessed through the physical memory map.
+pub struct BitmapFrameAllocator {
+  bitmap: &'static mut [u64],
+  frame_count: usize,
+  usable_frames: usize,
+  free_frames: usize,
+  next: usize,
+}
+
+impl BitmapFrameAllocator {
+  /// Create a frame allocator from the passed memory map.
+  ///
+  /// This function is unsafe because the caller must guarantee that the passed
+  /// memory map is valid, that all frames marked as `USABLE` in it are really
+  /// unused and that the physical memory offset has already been set.
+  pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+    // The bitmap covers every frame up to the end of the highest region that
+    // could ever hold RAM, so regions can be handed back to us later on.
+    let frame_count = memory_map.iter()
+      .filter(|r| r.region_type != MemoryRegionType::Reserved)
+      .map(|r| r.range.end_frame_number)
+      .max()
+      .unwrap_or(0) as usize;
+    let words = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+    let bitmap_bytes = (words * 8) as u64;
+
+    let bitmap_region = memory_map.iter()
+      .filter(|r| r.region_type == MemoryRegionType::Usable)
+      .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_bytes)
+      .expect("no usable region large enough for the frame bitmap");
+    let bitmap_start = PhysAddr::new(bitmap_region.range.start_addr());
+
+    let bitmap_ptr: *mut u64 = phys_to_virt(bitmap_start).as_mut_ptr();
+    let bitmap = slice::from_raw_parts_mut(bitmap_ptr, words);
+    for word in bitmap.iter_mut() {
+      *word = !0;
+    }
+
+    let mut allocator = BitmapFrameAllocator {
+      bitmap,
+      frame_count,
+      usable_frames: 0,
+      free_frames: 0,
+      next: 0,
+    };
+
+    for region in memory_map.iter().filter(|r| r.region_type == MemoryRegionType::Usable) {
+      allocator.add_frames(region.range.start_frame_number, region.range.end_frame_number);
+    }
+
+    // Keep the frames holding the bitmap away from everyone else
+    let bitmap_frames = (bitmap_bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+    let first = bitmap_start.as_u64() / FRAME_SIZE;
+    for index in first..first + bitmap_frames {
+      allocator.mark_used(index as usize);
+    }
+
+    allocator
+  }
+
+  /// Hands the frames in `start..end` (frame numbers) over to the allocator.
+  ///
+  /// This function is unsafe because the caller must guarantee that the frames
+  /// are RAM and are not used by anything else.
+  pub unsafe fn add_frames(&mut self, start: u64, end: u64) -> usize {
+    let end = core::cmp::min(end as usize, self.frame_count);
+    let mut added = 0;
+    for index in start as usize..end {
+      // Frame zero is never handed out so a null frame is always a bug
+      if index != 0 && self.is_used(index) {
+        self.set_used(index, false);
+        added += 1;
+      }
+    }
+    self.usable_frames += added;
+    self.free_frames += added;
+    if added > 0 && (start as usize) < self.next {
+      self.next = start as usize;
+    }
+    added
+  }
+
+  /// Amount of frames covered by the bitmap, usable or not.
+  pub fn frame_count(&self) -> usize {
+    self.frame_count
+  }
+
+  /// Total amount of frames managed by this allocator.
+  pub fn total_frames(&self) -> usize {
+    self.usable_frames
+  }
+
+  /// Amount of frames available for allocation.
+  pub fn free_frames(&self) -> usize {
+    self.free_frames
+  }
+
+  /// Amount of frames currently handed out.
+  pub fn used_frames(&self) -> usize {
+    self.usable_frames - self.free_frames
+  }
+
+  /// Length, in frames, of the longest run of free contiguous frames.
+  pub fn largest_free_run(&self) -> usize {
+    let mut largest = 0;
+    let mut current = 0;
+    for index in 0..self.frame_count {
+      if self.is_used(index) {
+        current = 0;
+      } else {
+        current += 1;
+        largest = largest.max(current);
+      }
+    }
+    largest
+  }
+
+  /// Allocates `count` physically contiguous frames and returns the first one.
+  pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+    self.allocate_contiguous_aligned(count, 1)
+  }
+
+  /// Allocates `count` physically contiguous frames whose first frame number
+  /// is a multiple of `align` (in frames).
+  pub fn allocate_contiguous_aligned(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+    self.allocate_contiguous_constrained(count, align, 0, self.frame_count)
+  }
+
+  /// Allocates `count` physically contiguous frames whose first frame number
+  /// is a multiple of `align`, that don't cross a multiple of `boundary` (0
+  /// for no boundary) and that all lie below frame number `limit`.
+  pub fn allocate_contiguous_constrained(
+    &mut self, count: usize, align: usize, boundary: usize, limit: usize
+  ) -> Option<PhysFrame> {
+    if count == 0 || align == 0 || count > self.free_frames {
+      return None;
+    }
+    if boundary != 0 && count > boundary {
+      return None;
+    }
+
+    let limit = core::cmp::min(limit, self.frame_count);
+    let mut start = 0;
+    while start + count <= limit {
+      if boundary != 0 && start / boundary != (start + count - 1) / boundary {
+        // Move to the next boundary, which is also suitably aligned
+        start = align_up(align_up(start + 1, boundary), align);
+        continue;
+      }
+
+      match (start..start + count).rev().find(|&index| self.is_used(index)) {
+        Some(used) => {
+          // Restart right after the last used frame in the window
+          start = align_up(used + 1, align);
+        },
+        None => {
+          for index in start..start + count {
+            self.mark_used(index);
+          }
+          return Some(frame_from_index(start));
+        },
+      }
+    }
+
+    None
+  }
+
+  /// Frees `count` contiguous frames starting at `frame`.
+  ///
+  /// This function is unsafe because the caller must guarantee that the frames
+  /// were allocated by this allocator and are no longer in use.
+  pub unsafe fn deallocate_contiguous(&mut self, frame: PhysFrame, count: usize) {
+    let first = frame_index(frame);
+    for index in first..first + count {
+      self.mark_free(index);
+    }
+  }
+
+  fn mark_used(&mut self, index: usize) {
+    if !self.is_used(index) {
+      self.set_used(index, true);
+      self.free_frames -= 1;
+    }
+  }
+
+  fn mark_free(&mut self, index: usize) {
+    if index >= self.frame_count || !self.is_used(index) {
+      panic!("frame {:#x} freed but was not allocated", index as u64 * FRAME_SIZE);
+    }
+    self.set_used(index, false);
+    self.free_frames += 1;
+    if index < self.next {
+      self.next = index;
+    }
+  }
+
+  fn is_used(&self, index: usize) -> bool {
+    self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+  }
+
+  fn set_used(&mut self, index: usize, used: bool) {
+    let word = &mut self.bitmap[index / BITS_PER_WORD];
+    let bit = 1 << (index % BITS_PER_WORD);
+    if used {
+      *word |= bit;
+    } else {
+      *word &= !bit;
+    }
+  }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+  fn allocate_frame(&mut self) -> Option<PhysFrame> {
+    if self.free_frames == 0 {
+      return None;
+    }
+
+    // Skip over fully used words, starting from the lowest known free frame
+    let words = self.bitmap.len();
+    let mut word_index = self.next / BITS_PER_WORD;
+    while word_index < words && self.bitmap[word_index] == !0 {
+      word_index += 1;
+    }
+    if word_index == words {
+      return None;
+    }
+
+    let index = word_index * BITS_PER_WORD + (!self.bitmap[word_index]).trailing_zeros() as usize;
+    if index >= self.frame_count {
+      return None;
+    }
+    self.mark_used(index);
+    self.next = index + 1;
+    Some(frame_from_index(index))
+  }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+  unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+    self.mark_free(frame_index(frame));
+  }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+  fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+    self.allocate_contiguous_aligned(FRAMES_PER_HUGE_FRAME, FRAMES_PER_HUGE_FRAME)
+      .map(|frame| PhysFrame::containing_address(frame.start_address()))
+  }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+  unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+    let first = PhysFrame::containing_address(frame.start_address());
+    self.deallocate_contiguous(first, FRAMES_PER_HUGE_FRAME);
+  }
+}
+
+fn frame_index(frame: PhysFrame) -> usize {
+  (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+fn frame_from_index(index: usize) -> PhysFrame {
+  PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+  (value + align - 1) / align * align
+}
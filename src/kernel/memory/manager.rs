@@ -0,0 +1,235 @@
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr, structures::paging::{FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB, Translate, mapper::{FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError}}};
+
+use crate::kernel::interrupts;
+use super::frame_allocator::BitmapFrameAllocator;
+
+/// Kernel-wide memory manager, set up by `memory::init`.
+static MEMORY_MANAGER: Mutex<Option<MemoryManager>> = Mutex::new(None);
+
+/// Installs the mapper and frame allocator built during boot as the
+/// kernel-wide memory manager.
+pub(super) fn install(memory_manager: MemoryManager) {
+  interrupts::without_interrupts(|| {
+    *MEMORY_MANAGER.lock() = Some(memory_manager);
+  });
+}
+
+/// Runs `f` with exclusive access to the kernel memory manager.
+///
+/// Interrupts are disabled while the lock is held so that interrupt handlers
+/// can also change mappings without deadlocking. The heap grows through this
+/// lock too, so `f` must not allocate from the heap.
+pub fn with_memory_manager<F, R>(f: F) -> R where F: FnOnce(&mut MemoryManager) -> R {
+  interrupts::without_interrupts(|| {
+    let mut memory_manager = MEMORY_MANAGER.lock();
+    f(memory_manager.as_mut().expect("memory manager not initialized"))
+  })
+}
+
+/// Like `with_memory_manager`, but gives up and returns `None` instead of
+/// spinning when the memory manager is already locked.
+///
+/// Meant for exception handlers, which may have interrupted the lock holder.
+pub fn try_with_memory_manager<F, R>(f: F) -> Option<R> where F: FnOnce(&mut MemoryManager) -> R {
+  interrupts::without_interrupts(|| {
+    let mut memory_manager = MEMORY_MANAGER.try_lock()?;
+    memory_manager.as_mut().map(f)
+  })
+}
+
+/// Runs `f` with exclusive access to the kernel frame allocator.
+pub fn with_frame_allocator<F, R>(f: F) -> R where F: FnOnce(&mut BitmapFrameAllocator) -> R {
+  with_memory_manager(|memory_manager| f(&mut memory_manager.frame_allocator))
+}
+
+/// Owns the active page table and the physical frame allocator.
+pub struct MemoryManager {
+  mapper: OffsetPageTable<'static>,
+  frame_allocator: BitmapFrameAllocator,
+}
+
+impl MemoryManager {
+  pub fn new(mapper: OffsetPageTable<'static>, frame_allocator: BitmapFrameAllocator) -> Self {
+    MemoryManager { mapper, frame_allocator }
+  }
+
+  pub fn frame_allocator(&mut self) -> &mut BitmapFrameAllocator {
+    &mut self.frame_allocator
+  }
+
+  /// Maps `page` to a newly allocated frame and returns that frame.
+  pub fn map_page(&mut self, page: Page, flags: PageTableFlags)
+    -> Result<PhysFrame, MapToError<Size4KiB>>
+  {
+    let frame: PhysFrame = self.frame_allocator
+      .allocate_frame()
+      .ok_or(MapToError::FrameAllocationFailed)?;
+
+    match unsafe { self.map_to(page, frame, flags) } {
+      Ok(()) => Ok(frame),
+      Err(err) => {
+        unsafe { self.frame_allocator.deallocate_frame(frame) };
+        Err(err)
+      },
+    }
+  }
+
+  /// Maps the 2 MiB `page` to a newly allocated 2 MiB frame.
+  pub fn map_huge_page(&mut self, page: Page<Size2MiB>, flags: PageTableFlags)
+    -> Result<PhysFrame<Size2MiB>, MapToError<Size2MiB>>
+  {
+    let frame = FrameAllocator::<Size2MiB>::allocate_frame(&mut self.frame_allocator)
+      .ok_or(MapToError::FrameAllocationFailed)?;
+
+    let mapped = unsafe { self.mapper.map_to(page, frame, flags, &mut self.frame_allocator) };
+    match mapped {
+      Ok(flush) => {
+        flush.flush();
+        Ok(frame)
+      },
+      Err(err) => {
+        unsafe { self.frame_allocator.deallocate_frame(frame) };
+        Err(err)
+      },
+    }
+  }
+
+  /// Maps `page` to the given `frame`.
+  ///
+  /// This function is unsafe because the caller must guarantee that the frame
+  /// is not already used in a way that conflicts with the new mapping.
+  pub unsafe fn map_to(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags)
+    -> Result<(), MapToError<Size4KiB>>
+  {
+    self.mapper
+      .map_to(page, frame, flags, &mut self.frame_allocator)?
+      .flush();
+    Ok(())
+  }
+
+  /// Removes the mapping of `page` and returns the frame it pointed to.
+  ///
+  /// The frame is not freed, see `unmap_and_free_page` for that.
+  pub fn unmap_page(&mut self, page: Page) -> Result<PhysFrame, UnmapError> {
+    let (frame, flush) = self.mapper.unmap(page)?;
+    flush.flush();
+    Ok(frame)
+  }
+
+  /// Removes the mapping of `page` and gives its frame back to the allocator.
+  ///
+  /// This function is unsafe because the caller must guarantee that the frame
+  /// is not mapped anywhere else.
+  pub unsafe fn unmap_and_free_page(&mut self, page: Page) -> Result<(), UnmapError> {
+    let frame = self.unmap_page(page)?;
+    self.frame_allocator.deallocate_frame(frame);
+    Ok(())
+  }
+
+  /// Maps `size` bytes starting at `start` to newly allocated frames.
+  ///
+  /// 2 MiB pages are used wherever the range is suitably aligned and a 2 MiB
+  /// frame is available. Either the whole range is mapped or nothing is.
+  pub fn map_range(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags)
+    -> Result<(), MapToError<Size4KiB>>
+  {
+    let start = start.align_down(Size4KiB::SIZE);
+    let end = (start + size).align_up(Size4KiB::SIZE);
+    let mut addr = start;
+    while addr < end {
+      if addr.is_aligned(Size2MiB::SIZE) && end - addr >= Size2MiB::SIZE {
+        let page = Page::<Size2MiB>::containing_address(addr);
+        // Falls back to 4 KiB pages if there is no 2 MiB frame or the
+        // area is already covered by a level 1 table
+        if self.map_huge_page(page, flags).is_ok() {
+          addr += Size2MiB::SIZE;
+          continue;
+        }
+      }
+
+      if let Err(err) = self.map_page(Page::containing_address(addr), flags) {
+        // Roll back what was mapped so far
+        unsafe { self.unmap_range(start, addr - start).ok() };
+        return Err(err);
+      }
+      addr += Size4KiB::SIZE;
+    }
+    Ok(())
+  }
+
+  /// Unmaps `size` bytes starting at `start` and frees the backing frames,
+  /// whatever page size they were mapped with.
+  ///
+  /// This function is unsafe because the caller must guarantee that the
+  /// frames are not mapped anywhere else.
+  pub unsafe fn unmap_range(&mut self, start: VirtAddr, size: u64) -> Result<(), UnmapError> {
+    let start = start.align_down(Size4KiB::SIZE);
+    let end = (start + size).align_up(Size4KiB::SIZE);
+    let mut addr = start;
+    while addr < end {
+      match self.mapper.translate(addr) {
+        TranslateResult::Mapped { frame: MappedFrame::Size2MiB(_), offset: 0, .. }
+          if end - addr >= Size2MiB::SIZE =>
+        {
+          let page = Page::<Size2MiB>::containing_address(addr);
+          let (frame, flush) = self.mapper.unmap(page)?;
+          flush.flush();
+          self.frame_allocator.deallocate_frame(frame);
+          addr += Size2MiB::SIZE;
+        },
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), .. } => {
+          self.unmap_and_free_page(Page::containing_address(addr))?;
+          addr += Size4KiB::SIZE;
+        },
+        TranslateResult::Mapped { .. } => return Err(UnmapError::ParentEntryHugePage),
+        TranslateResult::NotMapped => return Err(UnmapError::PageNotMapped),
+        TranslateResult::InvalidFrameAddress(addr) => return Err(UnmapError::InvalidFrameAddress(addr)),
+      }
+    }
+    Ok(())
+  }
+
+  /// Translates a virtual address to the physical address it is mapped to.
+  pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+    self.mapper.translate_addr(addr)
+  }
+
+  /// Replaces the flags of the mapping of `page`.
+  ///
+  /// This function is unsafe because changing flags can break memory safety,
+  /// e.g. by making pages in use read-only.
+  pub unsafe fn update_flags(&mut self, page: Page, flags: PageTableFlags)
+    -> Result<(), FlagUpdateError>
+  {
+    Mapper::<Size4KiB>::update_flags(&mut self.mapper, page, flags)?.flush();
+    Ok(())
+  }
+}
+
+/// Maps `page` to a newly allocated frame in the kernel page table.
+pub fn map_page(page: Page, flags: PageTableFlags) -> Result<PhysFrame, MapToError<Size4KiB>> {
+  with_memory_manager(|memory_manager| memory_manager.map_page(page, flags))
+}
+
+/// Removes the mapping of `page` from the kernel page table.
+pub fn unmap_page(page: Page) -> Result<PhysFrame, UnmapError> {
+  with_memory_manager(|memory_manager| memory_manager.unmap_page(page))
+}
+
+/// Maps `size` bytes at `start` to newly allocated frames in the kernel page table.
+pub fn map_range(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+  with_memory_manager(|memory_manager| memory_manager.map_range(start, size, flags))
+}
+
+/// Translates a virtual address using the kernel page table.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+  with_memory_manager(|memory_manager| memory_manager.translate(addr))
+}
+
+/// Replaces the flags of the mapping of `page` in the kernel page table.
+///
+/// This function is unsafe for the same reasons as `MemoryManager::update_flags`.
+pub unsafe fn update_flags(page: Page, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+  with_memory_manager(|memory_manager| memory_manager.update_flags(page, flags))
+}
@@ -0,0 +1,145 @@
+use core::mem;
+use volatile::Volatile;
+use x86_64::{PhysAddr, VirtAddr, structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB, mapper::MapToError}};
+
+use super::{vmalloc, with_memory_manager};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Caching policy of a device mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+  /// Every access goes straight to the device (PCD + PWT).
+  Uncached,
+  /// Reads may be cached, writes always reach the device (PWT).
+  WriteThrough,
+}
+
+impl CacheMode {
+  fn flags(self) -> PageTableFlags {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::WRITE_THROUGH;
+    match self {
+      CacheMode::Uncached => flags | PageTableFlags::NO_CACHE,
+      CacheMode::WriteThrough => flags,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum MmioError {
+  /// No kernel virtual range is left for the mapping.
+  OutOfVirtualSpace,
+  Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for MmioError {
+  fn from(err: MapToError<Size4KiB>) -> Self {
+    MmioError::Map(err)
+  }
+}
+
+/// A device register range mapped into a reserved vmalloc area.
+///
+/// The mapping is removed when the region is dropped.
+#[derive(Debug)]
+pub struct MmioRegion {
+  virt: VirtAddr,
+  phys: PhysAddr,
+  size: usize,
+}
+
+/// Maps `size` bytes of device memory starting at `phys` into kernel space.
+///
+/// This function is unsafe because the caller must guarantee that the range
+/// belongs to a device and not to RAM handed out by the frame allocator.
+pub unsafe fn ioremap(phys: PhysAddr, size: usize, mode: CacheMode)
+  -> Result<MmioRegion, MmioError>
+{
+  let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+  let page_offset = phys - first_frame.start_address();
+  let mapped_size = align_up(page_offset + size as u64, PAGE_SIZE);
+
+  let window_start = vmalloc::reserve(mapped_size, PAGE_SIZE)
+    .ok_or(MmioError::OutOfVirtualSpace)?;
+
+  let first_page = Page::<Size4KiB>::containing_address(window_start);
+  let mapped = with_memory_manager(|memory_manager| {
+    for index in 0..mapped_size / PAGE_SIZE {
+      if let Err(err) = memory_manager.map_to(first_page + index, first_frame + index, mode.flags()) {
+        for mapped in 0..index {
+          memory_manager.unmap_page(first_page + mapped).ok();
+        }
+        return Err(err);
+      }
+    }
+    Ok(())
+  });
+  if let Err(err) = mapped {
+    vmalloc::release(window_start);
+    return Err(err.into());
+  }
+
+  Ok(MmioRegion {
+    virt: window_start + page_offset,
+    phys,
+    size,
+  })
+}
+
+impl MmioRegion {
+  pub fn virt_addr(&self) -> VirtAddr {
+    self.virt
+  }
+
+  pub fn phys_addr(&self) -> PhysAddr {
+    self.phys
+  }
+
+  pub fn size(&self) -> usize {
+    self.size
+  }
+
+  /// Returns a volatile handle to the register of type `T` at `offset`.
+  pub fn register<T: Copy>(&mut self, offset: usize) -> &mut Volatile<T> {
+    self.check_access::<T>(offset);
+    unsafe { &mut *((self.virt.as_u64() + offset as u64) as *mut Volatile<T>) }
+  }
+
+  /// Reads the register of type `T` at `offset`.
+  pub fn read<T: Copy>(&self, offset: usize) -> T {
+    self.check_access::<T>(offset);
+    unsafe { ((self.virt.as_u64() + offset as u64) as *const T).read_volatile() }
+  }
+
+  /// Writes `value` to the register of type `T` at `offset`.
+  pub fn write<T: Copy>(&mut self, offset: usize, value: T) {
+    self.check_access::<T>(offset);
+    unsafe { ((self.virt.as_u64() + offset as u64) as *mut T).write_volatile(value) }
+  }
+
+  fn check_access<T>(&self, offset: usize) {
+    assert!(offset + mem::size_of::<T>() <= self.size, "MMIO access out of bounds");
+    assert!((self.virt.as_u64() + offset as u64) % mem::align_of::<T>() as u64 == 0,
+      "unaligned MMIO access");
+  }
+}
+
+impl Drop for MmioRegion {
+  fn drop(&mut self) {
+    let first_page = Page::<Size4KiB>::containing_address(self.virt);
+    let page_offset = self.virt - first_page.start_address();
+    let pages = align_up(page_offset + self.size as u64, PAGE_SIZE) / PAGE_SIZE;
+
+    // Device frames don't belong to the frame allocator, so only unmap them
+    with_memory_manager(|memory_manager| {
+      for index in 0..pages {
+        memory_manager.unmap_page(first_page + index).ok();
+      }
+    });
+    vmalloc::release(first_page.start_address());
+  }
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+  (value + align - 1) / align * align
+}
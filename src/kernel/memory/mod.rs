@@ -1,12 +1,42 @@
-use spin::Mutex;
-use x86_64::{PhysAddr, VirtAddr, structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB, Translate}};
+use core::sync::atomic::{AtomicU64, Ordering};
+use conquer_once::spin::OnceCell;
+use x86_64::{PhysAddr, VirtAddr, structures::paging::{OffsetPageTable, PageTable}};
 use bootloader::{BootInfo, bootinfo::{MemoryMap, MemoryRegionType}};
-use lazy_static::lazy_static;
 
+use crate::kprintln;
+
+pub mod address_space;
 pub mod allocator;
+pub mod cow;
+pub mod dma;
+pub mod fault;
+pub mod frame_allocator;
+pub mod manager;
+pub mod mmio;
+pub mod oom;
+pub mod slab;
+pub mod stack;
+pub mod vmalloc;
+
+use frame_allocator::BitmapFrameAllocator;
+use manager::MemoryManager;
+
+pub use manager::{map_page, map_range, translate, unmap_page, update_flags, try_with_memory_manager, with_frame_allocator, with_memory_manager};
+pub use fault::{handle_page_fault, register_lazy_region, unregister_lazy_region};
+
+// Atomic rather than locked so exception handlers can always translate
+static PHYS_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
 
-lazy_static! {
-  static ref PHYS_MEMORY_OFFSET: Mutex<u64> = Mutex::new(0);
+static MEMORY_MAP: OnceCell<&'static MemoryMap> = OnceCell::uninit();
+
+/// Physical frame usage, in frames of 4 KiB.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+  pub total: usize,
+  pub used: usize,
+  pub free: usize,
+  /// Longest run of physically contiguous free frames.
+  pub largest_free_block: usize,
 }
 
 /// Initialize a new OffsetPageTable.
@@ -15,24 +45,75 @@ lazy_static! {
 /// complete physical memory is mapped to virtual memory at the passed
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
-pub unsafe fn init(boot_info: &'static BootInfo) -> OffsetPageTable<'static> {
+pub unsafe fn init(boot_info: &'static BootInfo) {
+  // Saves phyisical mem offset for later
+  PHYS_MEMORY_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+  MEMORY_MAP.init_once(|| &boot_info.memory_map);
+
   let physical_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
   let level_4_table = active_level_4_table(physical_mem_offset);
   let mut mapper = OffsetPageTable::new(level_4_table, physical_mem_offset);
 
-  let mut frame_allocator = BootInfoFrameAllocator::init(&boot_info.memory_map);
+  let mut frame_allocator = BitmapFrameAllocator::init(&boot_info.memory_map);
   allocator::init_heap(&mut mapper, &mut frame_allocator)
     .expect("Heap Allocation failed");
+  oom::init();
+  vmalloc::init(mapper.level_4_table(), &mut frame_allocator);
+  address_space::init();
 
-  // Saves phyisical mem offset for later
-  let mut offset = PHYS_MEMORY_OFFSET.lock();
-  *offset = boot_info.physical_memory_offset;
+  manager::install(MemoryManager::new(mapper, frame_allocator));
+  cow::init();
+}
+
+/// Gives the frames of every `region_type` region back to the frame allocator
+/// and returns how many were added.
+unsafe fn reclaim_regions(region_type: MemoryRegionType) -> usize {
+  with_frame_allocator(|frame_allocator| {
+    memory_map().iter()
+      .filter(|r| r.region_type == region_type)
+      .map(|r| frame_allocator.add_frames(r.range.start_frame_number, r.range.end_frame_number))
+      .sum()
+  })
+}
 
-  mapper
+/// Reclaims the memory the bootloader ran from.
+///
+/// This function is unsafe because the caller must guarantee that nothing
+/// set up by the bootloader outside of the page tables, kernel stack and boot
+/// info (its GDT, for instance) is still in use.
+pub unsafe fn reclaim_boot_memory() -> usize {
+  let frames = reclaim_regions(MemoryRegionType::Bootloader);
+  kprintln!("[ MEMORY ] Reclaimed {} KiB of bootloader memory", frames * 4);
+  frames
+}
+
+/// Reclaims the memory holding the ACPI tables.
+///
+/// This function is unsafe because the caller must guarantee that every ACPI
+/// table that is still needed has been copied out of it first.
+pub unsafe fn reclaim_acpi_memory() -> usize {
+  let frames = reclaim_regions(MemoryRegionType::AcpiReclaimable);
+  kprintln!("[ MEMORY ] Reclaimed {} KiB of ACPI reclaimable memory", frames * 4);
+  frames
+}
+
+/// Returns the memory map handed over by the bootloader.
+pub fn memory_map() -> &'static MemoryMap {
+  *MEMORY_MAP.get().expect("memory not initialized")
+}
+
+/// Returns the current usage of physical frames.
+pub fn frame_stats() -> FrameStats {
+  with_frame_allocator(|frame_allocator| FrameStats {
+    total: frame_allocator.total_frames(),
+    used: frame_allocator.used_frames(),
+    free: frame_allocator.free_frames(),
+    largest_free_block: frame_allocator.largest_free_run(),
+  })
 }
 
 pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
-  VirtAddr::new(addr.as_u64() + *PHYS_MEMORY_OFFSET.lock())
+  VirtAddr::new(addr.as_u64() + PHYS_MEMORY_OFFSET.load(Ordering::Relaxed))
 }
 
 /// Returns a mutable reference to the active level 4 table.
@@ -54,47 +135,3 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
 
   &mut *page_table_ptr // unsafe
 }
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
-  memory_map: &'static MemoryMap,
-  next: usize,
-}
-
-impl BootInfoFrameAllocator {
-  /// Create a FrameAllocator from the passed memory map.
-  ///
-  /// This function is unsafe because the caller must guarantee that the passed
-  /// memory map is valid. The main requirement is that all frames that are marked
-  /// as `USABLE` in it are really unused.
-  pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-    BootInfoFrameAllocator {
-      memory_map,
-      next: 0,
-    }
-  }
-}
-
-impl BootInfoFrameAllocator {
-  /// Returns an iterator over the usable frames specified in the memory map.
-  fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-    // get usable regions from memory map
-    let regions = self.memory_map.iter();
-    let usable_regions = regions
-      .filter(|r| r.region_type == MemoryRegionType::Usable);
-    // map each region to its address range
-    let addr_ranges = usable_regions
-      .map(|r| r.range.start_addr()..r.range.end_addr());
-    // transform to an iterator of frame start addresses
-    let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-    // create `PhysFrame` types from the start addresses
-    frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-  }
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-  fn allocate_frame(&mut self) -> Option<PhysFrame> {
-      let frame = self.usable_frames().nth(self.next);
-      self.next += 1;
-      frame
-  }
-}
\ No newline at end of file
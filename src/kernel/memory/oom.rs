@@ -0,0 +1,80 @@
+// Out of memory handling
+//
+// When the heap can't grow any more the registered reclaimers get a chance
+// to free cached memory before the allocation is retried. If that doesn't
+// help either, the allocation error handler prints a report and halts.
+// Killing the offending task instead is out of reach: the kernel can't unwind
+// out of a task halfway through an allocation, so code that has to survive
+// running out of memory uses the `try_` APIs (`Executor::try_spawn`,
+// `Task::try_new`, `try_push_command`) and handles the error itself. The task
+// being polled is only tracked to name it in the report.
+
+use core::{alloc::Layout, sync::atomic::{AtomicU64, Ordering}};
+use spin::Mutex;
+
+use crate::kprintln;
+use super::{allocator, frame_stats, slab};
+
+const MAX_RECLAIMERS: usize = 8;
+
+/// Frees cached memory when the heap can't grow any more.
+///
+/// Returns roughly how many frames worth of memory it freed. Runs without the
+/// heap allocator locked, but memory is short, so it shouldn't allocate.
+pub type Reclaimer = fn() -> usize;
+
+static RECLAIMERS: Mutex<[Option<(&'static str, Reclaimer)>; MAX_RECLAIMERS]> =
+  Mutex::new([None; MAX_RECLAIMERS]);
+
+/// Identifier of the task being polled, reported when memory runs out.
+static CURRENT_TASK: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Registers a reclaimer that is run before an allocation is given up on.
+pub fn register_reclaimer(name: &'static str, reclaimer: Reclaimer) {
+  let mut reclaimers = RECLAIMERS.lock();
+  let slot = reclaimers.iter_mut()
+    .find(|slot| slot.is_none())
+    .expect("too many OOM reclaimers registered");
+  *slot = Some((name, reclaimer));
+}
+
+/// Registers the reclaimers of the memory subsystem itself.
+pub(super) fn init() {
+  register_reclaimer("heap-blocks", allocator::release_cached_blocks);
+  register_reclaimer("slab", slab::shrink_all);
+}
+
+/// Runs every registered reclaimer and returns the amount of frames freed.
+pub(super) fn reclaim() -> usize {
+  // Copy them out so a reclaimer may register others without deadlocking
+  let reclaimers = *RECLAIMERS.lock();
+  reclaimers.iter()
+    .flatten()
+    .map(|(_, reclaimer)| reclaimer())
+    .sum()
+}
+
+/// Records which task is currently running, `None` when idle.
+pub fn set_current_task(task_id: Option<u64>) {
+  CURRENT_TASK.store(task_id.unwrap_or(u64::MAX), Ordering::Relaxed);
+}
+
+/// Prints everything known about the memory state after `layout` could not
+/// be allocated.
+pub fn report(layout: Layout) {
+  let heap = allocator::heap_stats();
+  let frames = frame_stats();
+
+  kprintln!("[ MEMORY ] Out of memory allocating {} bytes (align {})", layout.size(), layout.align());
+  if allocator::heap_limit_reached() {
+    kprintln!("[ MEMORY ] Kernel heap reached its limit of {} KiB", heap.limit / 1024);
+  }
+  kprintln!("[ MEMORY ] Heap: {} KiB used, {} KiB free, {} KiB mapped",
+    heap.used / 1024, heap.free / 1024, heap.size / 1024);
+  kprintln!("[ MEMORY ] Frames: {} used, {} free, largest free block {} KiB",
+    frames.used, frames.free, frames.largest_free_block * 4);
+  match CURRENT_TASK.load(Ordering::Relaxed) {
+    u64::MAX => kprintln!("[ MEMORY ] No task was running"),
+    task_id => kprintln!("[ MEMORY ] Offending task: {}", task_id),
+  }
+}
@@ -0,0 +1,304 @@
+use alloc::vec::Vec;
+use core::{marker::PhantomData, mem, ops::{Deref, DerefMut}, ptr::{self, NonNull}, sync::atomic::{AtomicBool, Ordering}};
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+
+use crate::kernel::interrupts;
+use super::{phys_to_virt, with_frame_allocator, frame_allocator::FRAME_SIZE};
+
+/// Every cache that has allocated at least once, for listing purposes.
+static SLAB_CACHES: Mutex<Vec<&'static dyn SlabInfo>> = Mutex::new(Vec::new());
+
+/// Object counters of a single slab cache.
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+  pub name: &'static str,
+  pub object_size: usize,
+  pub objects_per_slab: usize,
+  pub slabs: usize,
+  pub in_use: usize,
+  pub free: usize,
+}
+
+/// Type-erased view of a cache, used to list and shrink all of them.
+pub trait SlabInfo: Sync {
+  fn stats(&self) -> SlabStats;
+
+  /// Gives the frames of every empty slab back to the frame allocator and
+  /// returns how many were freed.
+  fn shrink(&self) -> usize;
+}
+
+/// Returns the counters of every registered slab cache.
+pub fn caches() -> Vec<SlabStats> {
+  SLAB_CACHES.lock().iter().map(|cache| cache.stats()).collect()
+}
+
+/// Shrinks every registered slab cache, returning the amount of frames freed.
+///
+/// Doesn't allocate, and skips whatever is locked at the moment, so it can run
+/// as an OOM reclaimer.
+pub fn shrink_all() -> usize {
+  match SLAB_CACHES.try_lock() {
+    Some(caches) => caches.iter().map(|cache| cache.shrink()).sum(),
+    None => 0,
+  }
+}
+
+struct FreeObject {
+  next: *mut FreeObject,
+}
+
+/// Sits at the start of every slab, followed by its objects.
+struct SlabHeader {
+  next: *mut SlabHeader,
+  frame: PhysFrame,
+  free_list: *mut FreeObject,
+  in_use: usize,
+}
+
+struct SlabInner {
+  slab_list: *mut SlabHeader,
+  slabs: usize,
+  in_use: usize,
+  free: usize,
+}
+
+// The free list only points into slabs owned by the cache
+unsafe impl Send for SlabInner {}
+
+/// A cache of equally sized `T` objects carved out of whole physical frames.
+///
+/// Meant to be declared as a static:
+/// `static TASKS: SlabCache<Task> = SlabCache::new("task");`
+///
+/// Slabs are never given back on their own, `shrink_all` frees the empty ones.
+pub struct SlabCache<T> {
+  name: &'static str,
+  inner: Mutex<SlabInner>,
+  registered: AtomicBool,
+  _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> SlabCache<T> {
+  pub const fn new(name: &'static str) -> Self {
+    SlabCache {
+      name,
+      inner: Mutex::new(SlabInner {
+        slab_list: ptr::null_mut(),
+        slabs: 0,
+        in_use: 0,
+        free: 0,
+      }),
+      registered: AtomicBool::new(false),
+      _marker: PhantomData,
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    self.name
+  }
+
+  /// Size of a slot, big enough for either a `T` or a free list link.
+  fn object_size() -> usize {
+    let size = mem::size_of::<T>().max(mem::size_of::<FreeObject>());
+    let align = mem::align_of::<T>().max(mem::align_of::<FreeObject>());
+    (size + align - 1) / align * align
+  }
+
+  /// Offset of the first object in a slab, right after the header.
+  fn objects_offset() -> usize {
+    let align = mem::align_of::<T>().max(mem::align_of::<FreeObject>());
+    (mem::size_of::<SlabHeader>() + align - 1) / align * align
+  }
+
+  /// Amount of contiguous frames making up one slab. A power of two, as slabs
+  /// are aligned to their size for `free` to find them from an object.
+  fn slab_frames() -> usize {
+    let frame_size = FRAME_SIZE as usize;
+    ((Self::objects_offset() + Self::object_size() + frame_size - 1) / frame_size).next_power_of_two()
+  }
+
+  fn slab_size() -> usize {
+    Self::slab_frames() * FRAME_SIZE as usize
+  }
+
+  fn objects_per_slab() -> usize {
+    (Self::slab_size() - Self::objects_offset()) / Self::object_size()
+  }
+
+  /// Moves `value` into the cache. Gives it back if no memory is left.
+  pub fn alloc(&'static self, value: T) -> Result<SlabBox<T>, T>
+    where T: 'static
+  {
+    if !self.registered.swap(true, Ordering::AcqRel) {
+      SLAB_CACHES.lock().push(self);
+    }
+
+    let slot = interrupts::without_interrupts(|| {
+      let mut inner = self.inner.lock();
+      let mut slab = inner.slab_list;
+      while !slab.is_null() && unsafe { (*slab).free_list.is_null() } {
+        slab = unsafe { (*slab).next };
+      }
+      if slab.is_null() {
+        slab = Self::grow(&mut inner)?;
+      }
+
+      unsafe {
+        let object = (*slab).free_list;
+        (*slab).free_list = (*object).next;
+        (*slab).in_use += 1;
+        inner.free -= 1;
+        inner.in_use += 1;
+        Some(object as *mut T)
+      }
+    });
+
+    match slot {
+      Some(slot) => unsafe {
+        slot.write(value);
+        Ok(SlabBox { ptr: NonNull::new_unchecked(slot), cache: self })
+      },
+      None => Err(value),
+    }
+  }
+
+  /// Gives a slot back to its slab. The object must already be dropped.
+  unsafe fn free(&self, object: *mut T) {
+    interrupts::without_interrupts(|| {
+      let mut inner = self.inner.lock();
+      let slab = (object as usize & !(Self::slab_size() - 1)) as *mut SlabHeader;
+      assert!((*slab).in_use != 0, "slab {}: freed object {:p} is not in use", self.name, object);
+
+      let object = object as *mut FreeObject;
+      (*object).next = (*slab).free_list;
+      (*slab).free_list = object;
+      (*slab).in_use -= 1;
+      inner.in_use -= 1;
+      inner.free += 1;
+    });
+  }
+
+  /// Adds a new slab in front of the slab list and returns it.
+  fn grow(inner: &mut SlabInner) -> Option<*mut SlabHeader> {
+    let frame = with_frame_allocator(|frame_allocator| {
+      frame_allocator.allocate_contiguous_aligned(Self::slab_frames(), Self::slab_frames())
+    })?;
+
+    // The physical memory offset is huge page aligned, so the mapping is too
+    let slab = phys_to_virt(frame.start_address()).as_mut_ptr::<SlabHeader>();
+    let mut free_list = ptr::null_mut();
+    let objects = slab as u64 + Self::objects_offset() as u64;
+    for index in (0..Self::objects_per_slab()).rev() {
+      let object = (objects + (index * Self::object_size()) as u64) as *mut FreeObject;
+      unsafe { object.write(FreeObject { next: free_list }) };
+      free_list = object;
+    }
+    unsafe { slab.write(SlabHeader { next: inner.slab_list, frame, free_list, in_use: 0 }) };
+
+    inner.slab_list = slab;
+    inner.slabs += 1;
+    inner.free += Self::objects_per_slab();
+    Some(slab)
+  }
+}
+
+impl<T> SlabInfo for SlabCache<T> {
+  fn stats(&self) -> SlabStats {
+    let inner = interrupts::without_interrupts(|| {
+      let inner = self.inner.lock();
+      (inner.slabs, inner.in_use, inner.free)
+    });
+    SlabStats {
+      name: self.name,
+      object_size: Self::object_size(),
+      objects_per_slab: Self::objects_per_slab(),
+      slabs: inner.0,
+      in_use: inner.1,
+      free: inner.2,
+    }
+  }
+
+  fn shrink(&self) -> usize {
+    interrupts::without_interrupts(|| {
+      let mut guard = match self.inner.try_lock() {
+        Some(guard) => guard,
+        None => return 0,
+      };
+      let inner = &mut *guard;
+
+      let mut freed = 0;
+      let mut link: *mut *mut SlabHeader = &mut inner.slab_list;
+      unsafe {
+        while !(*link).is_null() {
+          let slab = *link;
+          if (*slab).in_use != 0 {
+            link = &mut (*slab).next;
+            continue;
+          }
+
+          *link = (*slab).next;
+          let frame = (*slab).frame;
+          with_frame_allocator(|frame_allocator| {
+            frame_allocator.deallocate_contiguous(frame, Self::slab_frames())
+          });
+          freed += Self::slab_frames();
+          inner.slabs -= 1;
+          inner.free -= Self::objects_per_slab();
+        }
+      }
+      freed
+    })
+  }
+}
+
+/// An owned `T` living in a slab cache, returned to it on drop.
+pub struct SlabBox<T: 'static> {
+  ptr: NonNull<T>,
+  cache: &'static SlabCache<T>,
+}
+
+unsafe impl<T: Send + 'static> Send for SlabBox<T> {}
+unsafe impl<T: Sync + 'static> Sync for SlabBox<T> {}
+
+impl<T: 'static> SlabBox<T> {
+  /// Consumes the box without dropping the object, see `from_raw`.
+  pub fn into_raw(this: Self) -> *mut T {
+    let ptr = this.ptr.as_ptr();
+    mem::forget(this);
+    ptr
+  }
+
+  /// Takes back ownership of an object given up with `into_raw`.
+  ///
+  /// This function is unsafe because the caller must guarantee that `ptr`
+  /// came from `into_raw` on a box of `cache`, and that it is only taken
+  /// back once.
+  pub unsafe fn from_raw(ptr: *mut T, cache: &'static SlabCache<T>) -> Self {
+    SlabBox { ptr: NonNull::new_unchecked(ptr), cache }
+  }
+}
+
+impl<T: 'static> Deref for SlabBox<T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { self.ptr.as_ref() }
+  }
+}
+
+impl<T: 'static> DerefMut for SlabBox<T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { self.ptr.as_mut() }
+  }
+}
+
+impl<T: 'static> Drop for SlabBox<T> {
+  fn drop(&mut self) {
+    unsafe {
+      ptr::drop_in_place(self.ptr.as_ptr());
+      self.cache.free(self.ptr.as_ptr());
+    }
+  }
+}
@@ -0,0 +1,158 @@
+use spin::Mutex;
+use x86_64::{VirtAddr, structures::paging::{Page, PageTableFlags}};
+
+use super::{translate, with_memory_manager, frame_allocator::FRAME_SIZE, vmalloc::{self, VmallocError}};
+
+const MAX_STACKS: usize = 64;
+
+#[derive(Debug)]
+pub enum StackError {
+  Vmalloc(VmallocError),
+  /// Every guard page slot is taken.
+  TooManyStacks,
+}
+
+impl From<VmallocError> for StackError {
+  fn from(err: VmallocError) -> Self {
+    StackError::Vmalloc(err)
+  }
+}
+
+/// The unmapped page right below a kernel stack.
+#[derive(Debug, Clone, Copy)]
+struct StackGuard {
+  name: &'static str,
+  start: VirtAddr,
+  /// End of the stack the page guards.
+  top: VirtAddr,
+}
+
+impl StackGuard {
+  fn contains(&self, addr: VirtAddr) -> bool {
+    addr >= self.start && addr < self.start + FRAME_SIZE
+  }
+
+  fn stack_contains(&self, addr: VirtAddr) -> bool {
+    addr >= self.start + FRAME_SIZE && addr < self.top
+  }
+}
+
+// A fixed array so fault handlers can search it without touching the heap
+static STACK_GUARDS: Mutex<[Option<StackGuard>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+fn register_guard(name: &'static str, start: VirtAddr, top: VirtAddr) -> Result<(), StackError> {
+  let mut guards = STACK_GUARDS.lock();
+  let slot = guards.iter_mut()
+    .find(|slot| slot.is_none())
+    .ok_or(StackError::TooManyStacks)?;
+  *slot = Some(StackGuard { name, start, top });
+  Ok(())
+}
+
+fn unregister_guard(start: VirtAddr) {
+  let mut guards = STACK_GUARDS.lock();
+  if let Some(slot) = guards.iter_mut().find(|slot| matches!(slot, Some(guard) if guard.start == start)) {
+    *slot = None;
+  }
+}
+
+/// Returns the name of the stack whose guard page contains `addr`.
+///
+/// Safe to call from exception handlers: gives up instead of waiting if the
+/// faulting code holds the lock.
+pub fn guard_page_owner(addr: VirtAddr) -> Option<&'static str> {
+  let guards = STACK_GUARDS.try_lock()?;
+  guards.iter()
+    .flatten()
+    .find(|guard| guard.contains(addr))
+    .map(|guard| guard.name)
+}
+
+/// Returns the lowest and highest address of the kernel stack containing
+/// `addr`.
+///
+/// Gives up instead of waiting if the lock is held, the heap debugging code
+/// calls it from inside allocations.
+pub fn stack_bounds(addr: VirtAddr) -> Option<(VirtAddr, VirtAddr)> {
+  let guards = STACK_GUARDS.try_lock()?;
+  guards.iter()
+    .flatten()
+    .find(|guard| guard.stack_contains(addr))
+    .map(|guard| (guard.start + FRAME_SIZE, guard.top))
+}
+
+/// Registers the guard page the bootloader left below the boot stack.
+///
+/// Must be called from the boot stack, once memory is initialized.
+pub fn register_boot_stack() {
+  let rsp: u64;
+  unsafe { asm!("mov {}, rsp", out(reg) rsp) };
+
+  // The bootloader leaves the pages around the stack unmapped, walk to them
+  let mut guard = Page::containing_address(VirtAddr::new(rsp));
+  while translate(guard.start_address()).is_some() {
+    guard -= 1;
+  }
+  let mut top = Page::containing_address(VirtAddr::new(rsp));
+  while translate(top.start_address()).is_some() {
+    top += 1;
+  }
+  register_guard("boot", guard.start_address(), top.start_address())
+    .expect("no slot for the boot stack guard");
+}
+
+/// A kernel stack in the vmalloc window with an unmapped guard page below it,
+/// so overflowing it faults instead of corrupting whatever lies underneath.
+#[derive(Debug)]
+pub struct KernelStack {
+  /// Start of the guard page, the mapped stack begins right after it.
+  guard: VirtAddr,
+  size: u64,
+}
+
+impl KernelStack {
+  /// Allocates a stack of `size` bytes, rounded up to whole pages. `name`
+  /// shows up in overflow reports.
+  pub fn new(name: &'static str, size: u64) -> Result<Self, StackError> {
+    let size = (size + FRAME_SIZE - 1) / FRAME_SIZE * FRAME_SIZE;
+    let guard = vmalloc::reserve(FRAME_SIZE + size, FRAME_SIZE)
+      .ok_or(VmallocError::OutOfVirtualSpace)?;
+
+    if let Err(err) = register_guard(name, guard, guard + FRAME_SIZE + size) {
+      vmalloc::release(guard);
+      return Err(err);
+    }
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    if let Err(err) = with_memory_manager(|memory_manager| memory_manager.map_range(guard + FRAME_SIZE, size, flags)) {
+      unregister_guard(guard);
+      vmalloc::release(guard);
+      return Err(VmallocError::Map(err).into());
+    }
+
+    Ok(KernelStack { guard, size })
+  }
+
+  /// Lowest usable address of the stack.
+  pub fn bottom(&self) -> VirtAddr {
+    self.guard + FRAME_SIZE
+  }
+
+  /// Address to load into `rsp`, the stack grows down from here.
+  pub fn top(&self) -> VirtAddr {
+    self.bottom() + self.size
+  }
+
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+}
+
+impl Drop for KernelStack {
+  fn drop(&mut self) {
+    unregister_guard(self.guard);
+    with_memory_manager(|memory_manager| unsafe { memory_manager.unmap_range(self.bottom(), self.size) })
+      .expect("kernel stack was not fully mapped");
+    vmalloc::release(self.guard);
+  }
+}
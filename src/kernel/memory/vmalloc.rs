@@ -0,0 +1,162 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{VirtAddr, structures::paging::{FrameAllocator, PageSize, PageTable, PageTableFlags, PhysFrame, Size2MiB, Size4KiB, mapper::MapToError}};
+
+use super::{phys_to_virt, with_memory_manager, frame_allocator::{BitmapFrameAllocator, FRAME_SIZE}};
+
+/// Start of the kernel virtual window handed out by this allocator.
+pub const VMALLOC_START: u64 = 0xFFFF_C000_0000_0000;
+/// Size of the window, a whole level 4 entry.
+pub const VMALLOC_SIZE: u64 = 512 * 1024 * 1024 * 1024; // 512 GiB
+
+/// Unmapped gap kept between two areas so overruns fault instead of
+/// silently corrupting the neighbour.
+pub const GUARD_SIZE: u64 = FRAME_SIZE;
+
+/// How the pages of an area are backed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+  /// Backed by frames from the frame allocator, freed with the area.
+  Allocated,
+  /// Only the virtual range is reserved, the owner maps it itself.
+  Reserved,
+}
+
+/// A range of kernel virtual memory handed out by this allocator.
+#[derive(Debug, Clone, Copy)]
+pub struct VmArea {
+  pub start: VirtAddr,
+  pub size: u64,
+  pub backing: Backing,
+}
+
+impl VmArea {
+  pub fn end(&self) -> VirtAddr {
+    self.start + self.size
+  }
+}
+
+#[derive(Debug)]
+pub enum VmallocError {
+  /// No gap in the window is large enough for the area.
+  OutOfVirtualSpace,
+  Map(MapToError<Size4KiB>),
+}
+
+/// Areas sorted by start address.
+static VM_AREAS: Mutex<Vec<VmArea>> = Mutex::new(Vec::new());
+
+/// Gives the window its level 3 table up front.
+///
+/// Address spaces copy the kernel's level 4 entries when they are created,
+/// so the entry must exist before then for later mappings to show up in all
+/// of them.
+pub(super) fn init(level_4_table: &mut PageTable, frame_allocator: &mut BitmapFrameAllocator) {
+  let entry = &mut level_4_table[VirtAddr::new(VMALLOC_START).p4_index()];
+  if !entry.is_unused() {
+    return;
+  }
+
+  let frame: PhysFrame = frame_allocator.allocate_frame()
+    .expect("no frame left for the vmalloc page table");
+  let table: *mut PageTable = phys_to_virt(frame.start_address()).as_mut_ptr();
+  unsafe { (*table).zero() };
+  entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+}
+
+/// Reserves `size` bytes of kernel virtual memory aligned to `align` without
+/// mapping anything. Release it with `release`.
+pub fn reserve(size: u64, align: u64) -> Option<VirtAddr> {
+  insert_area(size, align, Backing::Reserved)
+}
+
+/// Removes a reservation made with `reserve` and returns it.
+///
+/// The owner must already have unmapped everything it mapped in the range.
+pub fn release(start: VirtAddr) -> Option<VmArea> {
+  remove_area(start, Backing::Reserved)
+}
+
+/// Allocates `size` bytes of kernel virtual memory backed by (possibly
+/// non-contiguous) physical frames.
+///
+/// Areas of 2 MiB or more are 2 MiB aligned so they can use huge pages.
+pub fn vmalloc(size: u64) -> Result<VirtAddr, VmallocError> {
+  let size = align_up(size, FRAME_SIZE);
+  let align = if size >= Size2MiB::SIZE { Size2MiB::SIZE } else { FRAME_SIZE };
+  let start = insert_area(size, align, Backing::Allocated)
+    .ok_or(VmallocError::OutOfVirtualSpace)?;
+
+  let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+  if let Err(err) = with_memory_manager(|memory_manager| memory_manager.map_range(start, size, flags)) {
+    remove_area(start, Backing::Allocated);
+    return Err(VmallocError::Map(err));
+  }
+  Ok(start)
+}
+
+/// Frees an area allocated with `vmalloc`, unmapping it and giving its
+/// frames back. Returns `false` if `start` is not a vmalloc area.
+///
+/// This function is unsafe because the caller must guarantee that the area
+/// is no longer used.
+pub unsafe fn vfree(start: VirtAddr) -> bool {
+  match remove_area(start, Backing::Allocated) {
+    Some(area) => {
+      with_memory_manager(|memory_manager| memory_manager.unmap_range(area.start, area.size))
+        .expect("vmalloc area was not fully mapped");
+      true
+    },
+    None => false,
+  }
+}
+
+/// Returns the area containing `addr`, if any.
+pub fn find_area(addr: VirtAddr) -> Option<VmArea> {
+  VM_AREAS.lock().iter().find(|area| addr >= area.start && addr < area.end()).copied()
+}
+
+/// Returns a copy of every area currently handed out.
+pub fn areas() -> Vec<VmArea> {
+  VM_AREAS.lock().clone()
+}
+
+fn insert_area(size: u64, align: u64, backing: Backing) -> Option<VirtAddr> {
+  if size == 0 || !align.is_power_of_two() {
+    return None;
+  }
+  let size = align_up(size, FRAME_SIZE);
+  let align = core::cmp::max(align, FRAME_SIZE);
+  let window_end = VMALLOC_START + VMALLOC_SIZE;
+
+  let mut areas = VM_AREAS.lock();
+
+  // First fit, keeping a guard gap on both sides of every area
+  let mut index = 0;
+  let mut gap_start = VMALLOC_START;
+  loop {
+    let gap_end = areas.get(index).map(|area| area.start.as_u64()).unwrap_or(window_end);
+    let start = align_up(gap_start + GUARD_SIZE, align);
+    if start + size + GUARD_SIZE <= gap_end {
+      let start = VirtAddr::new(start);
+      areas.insert(index, VmArea { start, size, backing });
+      return Some(start);
+    }
+
+    match areas.get(index) {
+      Some(area) => gap_start = area.end().as_u64(),
+      None => return None,
+    }
+    index += 1;
+  }
+}
+
+fn remove_area(start: VirtAddr, backing: Backing) -> Option<VmArea> {
+  let mut areas = VM_AREAS.lock();
+  let index = areas.iter().position(|area| area.start == start && area.backing == backing)?;
+  Some(areas.remove(index))
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+  (value + align - 1) / align * align
+}
@@ -7,4 +7,9 @@ pub mod task;
 pub mod time;
 pub mod cmos;
 pub mod console;
-pub mod pci;
\ No newline at end of file
+pub mod pci;
+pub mod acpi;
+pub mod power;
+pub mod hpet;
+pub mod cpu;
+pub mod smp;
\ No newline at end of file
@@ -2,6 +2,7 @@ use alloc::vec::Vec;
 use bit_field::BitField;
 use lazy_static::lazy_static;
 use spin::Mutex;
+use x86_64::PhysAddr;
 use x86_64::instructions::port::Port;
 
 use crate::kprintln;
@@ -58,6 +59,27 @@ impl DeviceConfig {
         }
     }
 
+    /// Returns the physical address of a memory BAR, joining both halves of
+    /// 64-bit BARs. I/O space BARs, unused BARs and a 64-bit last BAR, which
+    /// has no register left for its high half, return `None`.
+    pub fn memory_bar_address(&self, index: usize) -> Option<PhysAddr> {
+        let bar = self.base_addresses[index];
+        if bar.get_bit(0) {
+            return None; // I/O space
+        }
+
+        let low = (bar & 0xFFFF_FFF0) as u64;
+        let addr = match bar.get_bits(1..3) {
+            0b10 => match self.base_addresses.get(index + 1) {
+                Some(&high) => low | ((high as u64) << 32),
+                None => return None, // The high half would be past the last BAR
+            },
+            _ => low,
+        };
+
+        if addr == 0 { None } else { Some(PhysAddr::new(addr)) }
+    }
+
     pub fn enable_bus_mastering(&mut self) {
         let mut register = ConfigRegister::new(self.bus, self.device, self.function, 0x04);
         let mut data = register.read();
@@ -85,6 +107,16 @@ pub fn init() {
     }
 }
 
+/// Reads the 32-bit configuration register containing `offset`.
+pub fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    ConfigRegister::new(bus, device, function, offset).read()
+}
+
+/// Writes the 32-bit configuration register containing `offset`.
+pub fn write_config(bus: u8, device: u8, function: u8, offset: u8, data: u32) {
+    ConfigRegister::new(bus, device, function, offset).write(data)
+}
+
 fn check_bus(bus: u8) {
     for device in 0..32 {
         check_device(bus, device);
@@ -0,0 +1,182 @@
+// Power off and reset
+
+use ::acpi::platform::address::{AddressSpace, GenericAddress};
+use x86_64::{PhysAddr, VirtAddr, instructions::{interrupts, port::Port, tables::lidt}, structures::DescriptorTablePointer};
+
+use crate::{kernel::{acpi, memory, pci, time}, kprintln};
+
+const SLP_EN: u16 = 1 << 13;
+const SCI_EN: u16 = 1 << 0;
+
+const PS2_STATUS_PORT: u16 = 0x64;
+const PS2_COMMAND_PORT: u16 = 0x64;
+const PS2_INPUT_BUFFER_FULL: u8 = 1 << 1;
+const PS2_PULSE_RESET: u8 = 0xFE;
+
+/// How long a controller gets to take the machine down before the next
+/// method is tried.
+const POWER_OFF_DELAY_NS: u64 = 500_000_000;
+const PS2_TIMEOUT_NS: u64 = 10_000_000;
+
+/// Reads a register described by a generic address. Only I/O and memory
+/// spaces are supported.
+fn read_register(register: &GenericAddress) -> Option<u64> {
+  match register.address_space {
+    AddressSpace::SystemIo => {
+      let port = register.address as u16;
+      Some(unsafe {
+        match register.bit_width {
+          8 => Port::<u8>::new(port).read() as u64,
+          32 => Port::<u32>::new(port).read() as u64,
+          _ => Port::<u16>::new(port).read() as u64,
+        }
+      })
+    },
+    AddressSpace::SystemMemory => {
+      let ptr = memory::phys_to_virt(PhysAddr::new(register.address)).as_u64();
+      Some(unsafe {
+        match register.bit_width {
+          8 => (ptr as *const u8).read_volatile() as u64,
+          32 => (ptr as *const u32).read_volatile() as u64,
+          64 => (ptr as *const u64).read_volatile(),
+          _ => (ptr as *const u16).read_volatile() as u64,
+        }
+      })
+    },
+    _ => None,
+  }
+}
+
+/// Writes a register described by a generic address. Returns `false` if its
+/// address space is not supported.
+fn write_register(register: &GenericAddress, value: u64) -> bool {
+  match register.address_space {
+    AddressSpace::SystemIo => {
+      let port = register.address as u16;
+      unsafe {
+        match register.bit_width {
+          8 => Port::<u8>::new(port).write(value as u8),
+          32 => Port::<u32>::new(port).write(value as u32),
+          _ => Port::<u16>::new(port).write(value as u16),
+        }
+      }
+      true
+    },
+    AddressSpace::SystemMemory => {
+      let ptr = memory::phys_to_virt(PhysAddr::new(register.address)).as_u64();
+      unsafe {
+        match register.bit_width {
+          8 => (ptr as *mut u8).write_volatile(value as u8),
+          32 => (ptr as *mut u32).write_volatile(value as u32),
+          64 => (ptr as *mut u64).write_volatile(value),
+          _ => (ptr as *mut u16).write_volatile(value as u16),
+        }
+      }
+      true
+    },
+    AddressSpace::PciConfigSpace => {
+      // Device, function and offset on bus 0, see the acpi crate docs
+      let device = (register.address >> 32) as u8;
+      let function = (register.address >> 16) as u8;
+      let offset = register.address as u8;
+      let shift = (offset % 4) * 8;
+      let dword = pci::read_config(0, device, function, offset);
+      let dword = (dword & !(0xFF << shift)) | ((value as u32 & 0xFF) << shift);
+      pci::write_config(0, device, function, offset, dword);
+      true
+    },
+    _ => false,
+  }
+}
+
+/// Hands the power management registers over from SMM to the OS, which
+/// some firmware requires before sleep states can be entered.
+fn enable_acpi_mode(fadt: &acpi::FadtInfo, pm1a_control: &GenericAddress) {
+  let enabled = read_register(pm1a_control).map_or(true, |value| value as u16 & SCI_EN != 0);
+  if enabled || fadt.smi_cmd_port == 0 || fadt.acpi_enable == 0 {
+    return;
+  }
+
+  unsafe { Port::<u8>::new(fadt.smi_cmd_port as u16).write(fadt.acpi_enable) };
+  let start = time::uptime();
+  while time::uptime() - start < 1.0 {
+    if read_register(pm1a_control).map_or(true, |value| value as u16 & SCI_EN != 0) {
+      return;
+    }
+    core::hint::spin_loop();
+  }
+  kprintln!("[ POWER ] Firmware did not switch to ACPI mode");
+}
+
+/// Enters the S5 soft off state. Only returns if it didn't work.
+pub fn shutdown() {
+  let fadt = match acpi::fadt() {
+    Some(fadt) => fadt,
+    None => return kprintln!("[ POWER ] No FADT, can't shut down"),
+  };
+  let pm1a_control = match fadt.pm1a_control_block {
+    Some(block) => block,
+    None => return kprintln!("[ POWER ] No PM1a control block, can't shut down"),
+  };
+  let sleep_type = match acpi::s5_sleep_type() {
+    Some(sleep_type) => sleep_type,
+    None => return kprintln!("[ POWER ] No \\_S5 object in the DSDT, can't shut down"),
+  };
+
+  enable_acpi_mode(fadt, &pm1a_control);
+  kprintln!("[ POWER ] Shutting down...");
+
+  interrupts::disable();
+  for (block, slp_typ) in [(Some(pm1a_control), sleep_type.pm1a), (fadt.pm1b_control_block, sleep_type.pm1b)].iter() {
+    if let Some(block) = block {
+      // Keep the other bits, SCI_EN in particular
+      let value = read_register(block).unwrap_or(0) as u16 & !(0x7 << 10);
+      write_register(block, (value | (slp_typ & 0x7) << 10 | SLP_EN) as u64);
+    }
+  }
+
+  // Powering off can take a moment
+  time::nanowait(POWER_OFF_DELAY_NS);
+  interrupts::enable();
+  kprintln!("[ POWER ] Shutdown failed");
+}
+
+/// Restarts the machine, trying the FADT reset register, then the keyboard
+/// controller if the FADT doesn't say there is none, and finally a triple
+/// fault.
+pub fn reboot() -> ! {
+  kprintln!("[ POWER ] Rebooting...");
+
+  if let Some(fadt) = acpi::fadt() {
+    if let Some(reset_register) = &fadt.reset_register {
+      if write_register(reset_register, fadt.reset_value as u64) {
+        time::sleep(0.5);
+      }
+    }
+  }
+
+  // Pulse the CPU reset line through the 8042
+  interrupts::disable();
+  if acpi::fadt().map_or(true, |fadt| fadt.has_8042) {
+    unsafe {
+      let mut status: Port<u8> = Port::new(PS2_STATUS_PORT);
+      let mut command: Port<u8> = Port::new(PS2_COMMAND_PORT);
+      let deadline = time::monotonic_ns() + PS2_TIMEOUT_NS;
+      while status.read() & PS2_INPUT_BUFFER_FULL != 0 && time::monotonic_ns() < deadline {
+        core::hint::spin_loop();
+      }
+      command.write(PS2_PULSE_RESET);
+    }
+    time::nanowait(POWER_OFF_DELAY_NS);
+  }
+
+  // With an empty IDT any exception escalates into a triple fault
+  unsafe {
+    lidt(&DescriptorTablePointer { limit: 0, base: VirtAddr::zero() });
+    interrupts::int3();
+  }
+
+  loop {
+    x86_64::instructions::hlt();
+  }
+}
@@ -0,0 +1,316 @@
+// Application processor bring-up
+//
+// Every usable processor in the MADT is started with the INIT-SIPI-SIPI
+// sequence. The startup IPI points it at a real-mode trampoline copied to a
+// free frame below 1 MiB, which switches straight to long mode on the kernel
+// page tables and calls `ap_main` on the processor's own kernel stack.
+
+use core::ptr;
+use x86_64::{VirtAddr, registers::control::Cr3, structures::paging::{FrameDeallocator, Page, PageTableFlags, PhysFrame}};
+
+use crate::{kernel::{cpu::{self, Cpu, CpuState}, gdt, interrupts::{self, apic::{self, LocalApic}}, memory::{self, stack::{KernelStack, StackError}}, time}, kprintln};
+
+global_asm!(r#"
+.pushsection .text.ap_trampoline, "ax"
+.global ap_trampoline_start
+.global ap_trampoline_end
+.global ap_trampoline_gdt
+.global ap_trampoline_gdtr
+.global ap_trampoline_long_mode
+.global ap_trampoline_long_mode_target
+.global ap_trampoline_cr3
+.global ap_trampoline_stack
+.global ap_trampoline_entry
+.global ap_trampoline_argument
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    // CS points at the trampoline, so offsets from its start work through DS
+    mov ax, cs
+    mov ds, ax
+
+    lgdt [ap_trampoline_gdtr - ap_trampoline_start]
+
+    // PAE
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+
+    mov eax, [ap_trampoline_cr3 - ap_trampoline_start]
+    mov cr3, eax
+
+    // Long mode and no-execute
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    // Protection, write protect and paging all at once
+    mov eax, cr0
+    or eax, (1 << 0) | (1 << 16) | (1 << 31)
+    mov cr0, eax
+
+    // Far jump into the 64-bit code segment, with a 32-bit offset
+    .byte 0x66, 0xEA
+ap_trampoline_long_mode_target:
+    .long 0
+    .word 0x08
+
+.code64
+ap_trampoline_long_mode:
+    // Null data selectors are fine in long mode
+    xor eax, eax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [rip + ap_trampoline_stack]
+    mov rdi, [rip + ap_trampoline_argument]
+    mov rax, [rip + ap_trampoline_entry]
+    call rax
+    ud2
+
+.align 8
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00AF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+ap_trampoline_gdtr:
+    .word 3 * 8 - 1
+    .long 0
+.align 8
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_argument:
+    .quad 0
+ap_trampoline_end:
+.popsection
+"#);
+
+extern "C" {
+  static ap_trampoline_start: u8;
+  static ap_trampoline_end: u8;
+  static ap_trampoline_gdt: u8;
+  static ap_trampoline_gdtr: u8;
+  static ap_trampoline_long_mode: u8;
+  static ap_trampoline_long_mode_target: u8;
+  static ap_trampoline_cr3: u8;
+  static ap_trampoline_stack: u8;
+  static ap_trampoline_entry: u8;
+  static ap_trampoline_argument: u8;
+}
+
+const AP_STACK_SIZE: u64 = 4096 * 16;
+
+/// Frames below 1 MiB, the only ones a startup IPI can point at.
+const LOW_MEMORY_FRAMES: usize = 256;
+
+const INIT_DELAY_NS: u64 = 10_000_000;
+const STARTUP_DELAY_NS: u64 = 200_000;
+const ONLINE_TIMEOUT_NS: u64 = 100_000_000;
+
+/// The trampoline copied to low memory, identity mapped while it exists so the
+/// instruction enabling paging is followed by a mapped one.
+struct Trampoline {
+  frame: PhysFrame,
+  identity_mapped: bool,
+}
+
+fn symbol_offset(symbol: &u8) -> u64 {
+  symbol as *const u8 as u64 - unsafe { &ap_trampoline_start as *const u8 as u64 }
+}
+
+impl Trampoline {
+  fn new() -> Result<Self, &'static str> {
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    if cr3 > u32::MAX as u64 {
+      return Err("the kernel page tables are above 4 GiB");
+    }
+
+    let frame = memory::with_frame_allocator(|frame_allocator| {
+      frame_allocator.allocate_contiguous_constrained(1, 1, 0, LOW_MEMORY_FRAMES)
+    }).ok_or("no free frame below 1 MiB")?;
+
+    let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    let mapped = memory::with_memory_manager(|memory_manager| {
+      match memory_manager.translate(page.start_address()) {
+        Some(addr) if addr == frame.start_address() => Ok(false),
+        Some(_) => Err("the trampoline address is already mapped"),
+        None => unsafe { memory_manager.map_to(page, frame, PageTableFlags::PRESENT) }
+          .map(|_| true)
+          .map_err(|_| "failed to identity map the trampoline"),
+      }
+    });
+    let identity_mapped = match mapped {
+      Ok(identity_mapped) => identity_mapped,
+      Err(err) => {
+        memory::with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate_frame(frame) });
+        return Err(err);
+      }
+    };
+
+    let trampoline = Trampoline { frame, identity_mapped };
+    unsafe {
+      let start = &ap_trampoline_start as *const u8;
+      let length = &ap_trampoline_end as *const u8 as usize - start as usize;
+      ptr::copy_nonoverlapping(start, trampoline.virt_addr().as_mut_ptr(), length);
+
+      // Everything the real-mode code uses as a linear address
+      let base = frame.start_address().as_u64();
+      trampoline.write::<u32>(&ap_trampoline_gdtr, 2, (base + symbol_offset(&ap_trampoline_gdt)) as u32);
+      trampoline.write::<u32>(&ap_trampoline_long_mode_target, 0, (base + symbol_offset(&ap_trampoline_long_mode)) as u32);
+      trampoline.write::<u64>(&ap_trampoline_cr3, 0, cr3);
+      trampoline.write::<u64>(&ap_trampoline_entry, 0, ap_main as usize as u64);
+    }
+    Ok(trampoline)
+  }
+
+  fn virt_addr(&self) -> VirtAddr {
+    memory::phys_to_virt(self.frame.start_address())
+  }
+
+  /// Startup IPI vector, the page number the processor starts at.
+  fn vector(&self) -> u8 {
+    (self.frame.start_address().as_u64() / 4096) as u8
+  }
+
+  /// Patches the copy of `symbol`, `offset` bytes into it.
+  fn write<T>(&self, symbol: &u8, offset: u64, value: T) {
+    let address = self.virt_addr() + symbol_offset(symbol) + offset;
+    unsafe { (address.as_u64() as *mut T).write_unaligned(value) };
+  }
+
+  /// Sets up the stack and argument the next processor to start picks up.
+  fn prepare(&self, stack_top: VirtAddr, cpu_index: usize) {
+    unsafe {
+      self.write::<u64>(&ap_trampoline_stack, 0, stack_top.as_u64());
+      self.write::<u64>(&ap_trampoline_argument, 0, cpu_index as u64);
+    }
+  }
+}
+
+impl Drop for Trampoline {
+  fn drop(&mut self) {
+    if self.identity_mapped {
+      let page = Page::containing_address(VirtAddr::new(self.frame.start_address().as_u64()));
+      memory::with_memory_manager(|memory_manager| memory_manager.unmap_page(page))
+        .expect("trampoline was not mapped");
+    }
+    memory::with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate_frame(self.frame) });
+  }
+}
+
+/// Starts every application processor the MADT describes.
+///
+/// Must be called after `cpu::init` and `time::calibrate_tsc`.
+pub fn init() {
+  if !apic::is_enabled() {
+    kprintln!("[ SMP ] No local APIC, only the bootstrap processor runs.");
+    return;
+  }
+  let pending = cpu::cpus().iter().filter(|cpu| cpu.state() == CpuState::Offline).count();
+  if pending == 0 {
+    kprintln!("[ SMP ] No application processors to start.");
+    return;
+  }
+
+  let trampoline = match Trampoline::new() {
+    Ok(trampoline) => trampoline,
+    Err(err) => {
+      kprintln!("[ SMP ] Can't start application processors: {}", err);
+      return;
+    }
+  };
+  kprintln!("[ SMP ] Starting {} application processors, trampoline at {:#x}...", pending,
+    trampoline.frame.start_address().as_u64());
+
+  for cpu in cpu::cpus().iter().filter(|cpu| cpu.state() == CpuState::Offline) {
+    if let Err(err) = start(cpu, &trampoline) {
+      cpu.set_state(CpuState::Failed);
+      kprintln!("[ SMP ] CPU {} (APIC id {}) failed to start: {}", cpu.index(), cpu.apic_id(), err);
+    }
+  }
+  drop(trampoline);
+
+  kprintln!("[ SMP ] {} of {} processors online.", cpu::online_count(), cpu::cpus().len());
+}
+
+fn start(cpu: &'static Cpu, trampoline: &Trampoline) -> Result<(), &'static str> {
+  let stack = KernelStack::new("ap", AP_STACK_SIZE).map_err(stack_error)?;
+  let tables = gdt::new_cpu_tables().map_err(stack_error)?;
+  trampoline.prepare(stack.top(), cpu.index());
+  cpu.set_stack(stack);
+  cpu.set_tables(tables);
+  cpu.set_state(CpuState::Starting);
+
+  LocalApic::send_init(cpu.apic_id());
+  time::nanowait(INIT_DELAY_NS);
+
+  // A second startup IPI is only needed if the first one got lost
+  for _ in 0..2 {
+    LocalApic::send_startup(cpu.apic_id(), trampoline.vector());
+    time::nanowait(STARTUP_DELAY_NS);
+    if cpu.state() != CpuState::Starting {
+      break;
+    }
+  }
+
+  let deadline = time::monotonic_ns() + ONLINE_TIMEOUT_NS;
+  while time::monotonic_ns() < deadline {
+    if cpu.state() == CpuState::Online {
+      kprintln!("[ SMP ] CPU {} (APIC id {}) online.", cpu.index(), cpu.apic_id());
+      return Ok(());
+    }
+    core::hint::spin_loop();
+  }
+
+  if !cpu.transition(CpuState::Starting, CpuState::Failed) {
+    return Ok(());
+  }
+
+  // It may still be on its way through the trampoline, which is about to be
+  // prepared for the next processor or freed. Another INIT puts it back into
+  // wait-for-SIPI wherever it is, and nothing sends it a startup IPI again.
+  LocalApic::send_init(cpu.apic_id());
+  time::nanowait(INIT_DELAY_NS);
+  Err("timed out")
+}
+
+fn stack_error(err: StackError) -> &'static str {
+  match err {
+    StackError::TooManyStacks => "no guard page slot left for its stacks",
+    StackError::Vmalloc(_) => "no memory for its stacks",
+  }
+}
+
+/// Entry point of application processors, called by the trampoline.
+extern "C" fn ap_main(cpu_index: u64) -> ! {
+  let cpu = &cpu::cpus()[cpu_index as usize];
+
+  gdt::load_cpu_tables(cpu.tables().expect("application processor without a GDT"));
+  // Before the IDT, exception handlers look up the running processor
+  cpu.install();
+  interrupts::init_ap();
+  apic::init_ap();
+
+  if !cpu.transition(CpuState::Starting, CpuState::Online) {
+    // The bootstrap processor gave up on us, wait for its INIT
+    loop {
+      x86_64::instructions::interrupts::disable();
+      x86_64::instructions::hlt();
+    }
+  }
+
+  // Nothing is scheduled on application processors yet
+  loop {
+    x86_64::instructions::interrupts::enable_and_hlt();
+  }
+}
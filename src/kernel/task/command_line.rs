@@ -1,4 +1,4 @@
-use alloc::{string::String, vec::Vec};
+use alloc::{collections::TryReserveError, string::String, vec::Vec};
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
 use futures_util::stream::StreamExt;
@@ -9,21 +9,50 @@ use futures_util::task::AtomicWaker;
 
 use crate::{kernel::console::{command_line}, kprintln};  
 
+#[derive(Debug)]
+pub enum CommandQueueError {
+  Uninitialized,
+  Full,
+  OutOfMemory(TryReserveError),
+}
+
+impl From<TryReserveError> for CommandQueueError {
+  fn from(err: TryReserveError) -> Self {
+    CommandQueueError::OutOfMemory(err)
+  }
+}
+
 /// Called by the keyboard interrupt handler
 ///
 /// Must not block or allocate.
 pub(crate) fn push_command(command: &str, args: Vec<String>) {
-  if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-    if let Err(_) = queue.push((String::from(command), args)) {
-      kprintln!("WARNING: scancode queue full; dropping keyboard input");
-    } else {
-      WAKER.wake();
-    }
-  } else {
-    kprintln!("WARNING: scancode queue uninitialized");
+  match try_push_command(command, args) {
+    Ok(()) => {},
+    Err(CommandQueueError::Uninitialized) => kprintln!("WARNING: scancode queue uninitialized"),
+    Err(CommandQueueError::Full) => kprintln!("WARNING: scancode queue full; dropping keyboard input"),
+    Err(CommandQueueError::OutOfMemory(_)) => kprintln!("WARNING: out of memory; dropping command {}", command),
   }
 }
 
+/// Queues a command to be run, reporting why it couldn't be instead of
+/// printing a warning.
+pub(crate) fn try_push_command(command: &str, args: Vec<String>) -> Result<(), CommandQueueError> {
+  let queue = SCANCODE_QUEUE.try_get()
+    .map_err(|_| CommandQueueError::Uninitialized)?;
+  if queue.is_full() {
+    return Err(CommandQueueError::Full);
+  }
+
+  let mut owned_command = String::new();
+  owned_command.try_reserve(command.len())?;
+  owned_command.push_str(command);
+
+  queue.push((owned_command, args))
+    .map_err(|_| CommandQueueError::Full)?;
+  WAKER.wake();
+  Ok(())
+}
+
 pub async fn handle_command_runs() {
   let mut commands_to_run = CommandLineStream::new();
   while let Some(command_to_run) = commands_to_run.next().await {
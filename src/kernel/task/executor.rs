@@ -1,56 +1,84 @@
 use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc};
-use core::task::{Context, Poll, Waker};
+use alloc::{sync::Arc, vec::Vec};
+use core::{sync::atomic::{self, AtomicUsize, Ordering}, task::{Context, Poll, RawWaker, RawWakerVTable, Waker}};
 use crossbeam_queue::ArrayQueue;
-use alloc::task::Wake;
+
+use crate::kernel::{memory::{oom, slab::{SlabBox, SlabCache}}, time};
+
+static TASKS: SlabCache<Task> = SlabCache::new("task");
+static TASK_WAKERS: SlabCache<TaskWaker> = SlabCache::new("task-waker");
 
 pub struct Executor {
-  tasks: BTreeMap<TaskId, Task>,
+  /// Sorted by id. A `Vec` rather than a map so that spawning can reserve
+  /// room up front and fail instead of aborting when memory runs out.
+  tasks: Vec<SpawnedTask>,
   task_queue: Arc<ArrayQueue<TaskId>>,
-  waker_cache: BTreeMap<TaskId, Waker>,
+}
+
+/// A task and its waker, allocated together when it is spawned so that
+/// polling never needs memory.
+struct SpawnedTask {
+  task: SlabBox<Task>,
+  waker: Waker,
 }
 
 impl Executor {
   pub fn new() -> Self {
     Executor {
-      tasks: BTreeMap::new(),
+      tasks: Vec::new(),
       task_queue: Arc::new(ArrayQueue::new(100)),
-      waker_cache: BTreeMap::new(),
     }
   }
 
   pub fn spawn(&mut self, task: Task) {
+    if self.try_spawn(task).is_err() {
+      panic!("Task Queue is full!");
+    }
+  }
+
+  /// Like `spawn`, but hands the task back instead of panicking when the
+  /// task queue is full or there is no memory left to keep track of it.
+  pub fn try_spawn(&mut self, task: Task) -> Result<(), Task> {
+    if self.task_queue.is_full() || self.tasks.try_reserve(1).is_err() {
+      return Err(task);
+    }
+
     let task_id = task.id;
-    if self.tasks.insert(task.id, task).is_some() {
-      panic!("Task with same ID already exists in tasks queue");
+    let waker = match TaskWaker::new(task_id, self.task_queue.clone()) {
+      Some(waker) => waker,
+      None => return Err(task),
+    };
+    let task = TASKS.alloc(task)?;
+    match find_task(&self.tasks, task_id) {
+      Ok(_) => panic!("Task with same ID already exists in tasks queue"),
+      Err(index) => self.tasks.insert(index, SpawnedTask { task, waker }),
     }
 
     self.task_queue.push(task_id).expect("Task Queue is full!");
+    Ok(())
   }
 
   pub fn run_ready_tasks(&mut self) {
     let Self {
       tasks,
       task_queue,
-      waker_cache,
     } = self;
 
     while let Ok(task_id) = task_queue.pop() {
-      let task = match tasks.get_mut(&task_id) {
-        Some(task) => task,
-        None => continue,
+      let index = match find_task(tasks, task_id) {
+        Ok(index) => index,
+        Err(_) => continue,
       };
+      let SpawnedTask { task, waker } = &mut tasks[index];
 
-      let waker = waker_cache
-        .entry(task_id)
-        .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-      
       let mut context = Context::from_waker(waker);
-      match task.poll(&mut context) {
+      oom::set_current_task(Some(task_id.0));
+      let poll = task.poll(&mut context);
+      oom::set_current_task(None);
+      match poll {
         Poll::Ready(()) => {
-          // task done -> remove it and its cache
-          tasks.remove(&task_id);
-          waker_cache.remove(&task_id);
+          // task done -> remove it and its waker
+          tasks.remove(index);
         }
         Poll::Pending => {}
       };
@@ -70,6 +98,8 @@ impl Executor {
     interrupts::disable();
 
     if self.task_queue.is_empty() {
+      // Only wake up early enough for the next sleeping task, if any
+      time::program_next_deadline();
       enable_and_hlt();
     } else {
       interrupts::enable();
@@ -77,17 +107,30 @@ impl Executor {
   }
 }
 
+/// Position of the task with `task_id` in the sorted task list, or where it
+/// would have to be inserted.
+fn find_task(tasks: &[SpawnedTask], task_id: TaskId) -> Result<usize, usize> {
+  tasks.binary_search_by_key(&task_id, |spawned| spawned.task.id)
+}
+
+/// Reference counted by hand, as wakers live in the `task-waker` slab cache
+/// rather than in an `Arc`.
 struct TaskWaker {
   task_id: TaskId,
   task_queue: Arc<ArrayQueue<TaskId>>,
+  references: AtomicUsize,
 }
 
 impl TaskWaker {
-  fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-    Waker::from(Arc::new(TaskWaker {
+  fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Option<Waker> {
+    let waker = TASK_WAKERS.alloc(TaskWaker {
       task_id,
       task_queue,
-    }))
+      references: AtomicUsize::new(1),
+    }).ok()?;
+
+    let data = SlabBox::into_raw(waker) as *const ();
+    Some(unsafe { Waker::from_raw(RawWaker::new(data, &WAKER_VTABLE)) })
   }
 
   fn wake_task(&self) {
@@ -95,12 +138,25 @@ impl TaskWaker {
   }
 }
 
-impl Wake for TaskWaker {
-  fn wake(self: Arc<Self>) {
-    self.wake_task();
-  }
+const WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(clone_waker, wake_waker, wake_waker_by_ref, drop_waker);
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+  (*(data as *const TaskWaker)).references.fetch_add(1, Ordering::Relaxed);
+  RawWaker::new(data, &WAKER_VTABLE)
+}
+
+unsafe fn wake_waker(data: *const ()) {
+  wake_waker_by_ref(data);
+  drop_waker(data);
+}
+
+unsafe fn wake_waker_by_ref(data: *const ()) {
+  (*(data as *const TaskWaker)).wake_task();
+}
 
-  fn wake_by_ref(self: &Arc<Self>) {
-    self.wake_task();
+unsafe fn drop_waker(data: *const ()) {
+  if (*(data as *const TaskWaker)).references.fetch_sub(1, Ordering::Release) == 1 {
+    atomic::fence(Ordering::Acquire);
+    drop(SlabBox::from_raw(data as *mut TaskWaker, &TASK_WAKERS));
   }
 }
\ No newline at end of file
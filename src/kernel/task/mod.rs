@@ -1,8 +1,10 @@
 
 use core::{future::Future, pin::Pin, task::{Context, Poll}};
-use alloc::boxed::Box;
+use alloc::{alloc::AllocError, boxed::Box, sync::Arc};
 use core::sync::atomic::{AtomicU64, Ordering};
 
+use crate::kernel::memory::address_space::{self, AddressSpace};
+
 pub mod keyboard;
 pub mod executor;
 pub mod command_line;
@@ -13,6 +15,8 @@ struct TaskId(u64);
 pub struct Task {
   id: TaskId,
   future: Pin<Box<dyn Future<Output = ()>>>,
+  /// Page table loaded while the task runs, the kernel's if `None`.
+  address_space: Option<Arc<AddressSpace>>,
 }
 
 impl Task {
@@ -20,11 +24,39 @@ impl Task {
     Task {
       id: TaskId::new(),
       future: Box::pin(future),
+      address_space: None,
+    }
+  }
+
+  /// Creates a task that runs inside `address_space`.
+  pub fn with_address_space(future: impl Future<Output = ()> + 'static, address_space: Arc<AddressSpace>) -> Task {
+    Task {
+      address_space: Some(address_space),
+      ..Task::new(future)
     }
   }
 
+  /// Like `new`, but returns an error instead of aborting when the future
+  /// can't be moved to the heap.
+  pub fn try_new(future: impl Future<Output = ()> + 'static) -> Result<Task, AllocError> {
+    let future = Box::try_new(future)?;
+    Ok(Task {
+      id: TaskId::new(),
+      future: Box::into_pin(future),
+      address_space: None,
+    })
+  }
+
   fn poll(&mut self, context: &mut Context) -> Poll<()> {
-    self.future.as_mut().poll(context)
+    match &self.address_space {
+      Some(address_space) => {
+        address_space.activate();
+        let poll = self.future.as_mut().poll(context);
+        address_space::activate_kernel();
+        poll
+      },
+      None => self.future.as_mut().poll(context),
+    }
   }
 }
 
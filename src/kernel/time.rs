@@ -1,6 +1,8 @@
-use core::{convert::TryInto, sync::atomic::{AtomicUsize, AtomicU64, Ordering}};
+use alloc::vec::Vec;
+use core::{arch::x86_64::{__cpuid, _rdtsc}, convert::TryInto, future::Future, pin::Pin, sync::atomic::{AtomicBool, AtomicUsize, AtomicU64, Ordering}, task::{Context, Poll, Waker}};
+use spin::Mutex;
 use x86_64::{instructions::port::Port, structures::idt::InterruptStackFrame};
-use crate::{kernel::{interrupts}};
+use crate::{kernel::{hpet, interrupts::{self, apic, apic_timer}}, kprintln};
 
 const PIT_FREQUENCY: f64 = 1_193_181.666 * 0.7;
 const PIT_DIVIDER: usize = 1193;
@@ -8,10 +10,47 @@ const PIT_INTERVAL: f64 = (PIT_DIVIDER as f64) / PIT_FREQUENCY;
 
 const PIT_COMMAND_PORT: u16 = 0x43;
 const PIT_DATA_PORT: u16 = 0x40;
+const PIT_CHANNEL_2_PORT: u16 = 0x42;
+const PIT_GATE_PORT: u16 = 0x61;
+
+/// The real PIT input clock, used for TSC calibration.
+const PIT_INPUT_FREQUENCY: u64 = 1_193_182;
+const CALIBRATION_MS: u64 = 10;
+/// Port reads before giving up on PIT channel 2, each takes about a
+/// microsecond so this is far longer than `CALIBRATION_MS`.
+const PIT_CALIBRATION_MAX_POLLS: u32 = 1_000_000;
+
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
 
 pub static PIT_TICKS: AtomicUsize = AtomicUsize::new(0);
 static LAST_RTC_UPDATE: AtomicUsize = AtomicUsize::new(0);
-static CLOCKS_PER_NANOSECOND: AtomicU64 = AtomicU64::new(0);
+/// TSC ticks per second, zero until calibrated.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// TSC value `monotonic_ns` counts from.
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+static TSC_INVARIANT: AtomicBool = AtomicBool::new(false);
+/// HPET value `monotonic_ns` counts from when the TSC is not invariant.
+static MONOTONIC_HPET_START: AtomicU64 = AtomicU64::new(0);
+
+/// PIT based uptime when the HPET took over, as `f64` bits. Zero while the
+/// PIT is still the clock source.
+static HPET_START_UPTIME: AtomicU64 = AtomicU64::new(0);
+/// Uptime when the PIT tick was stopped, as `f64` bits, and `monotonic_ns`
+/// at that moment. Zero while the PIT still ticks.
+static PIT_STOP_UPTIME: AtomicU64 = AtomicU64::new(0);
+static PIT_STOP_NS: AtomicU64 = AtomicU64::new(0);
+
+/// A pending sleep. Its waker is `None` for the blocking `sleep`.
+struct Timer {
+  id: u64,
+  deadline: u64,
+  waker: Option<Waker>,
+  fired: bool,
+}
+
+// Also taken by the timer interrupt, so only lock it with interrupts disabled
+static TIMERS: Mutex<Vec<Timer>> = Mutex::new(Vec::new());
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
 
 fn set_pit_frequency_divider(divider: u16) {
   interrupts::without_interrupts(|| {
@@ -28,18 +67,7 @@ fn set_pit_frequency_divider(divider: u16) {
 }
 
 fn rdtsc() -> u64 {
-  let upper: u64;
-let lower: u64;
-unsafe {
-    llvm_asm!("rdtsc"
-         : "={rax}"(lower), 
-           "={rdx}"(upper)
-         :
-         :
-         : "volatile"
-    );
-}
-upper << 32 | lower
+  unsafe { _rdtsc() }
 }
 
 pub fn time_between_ticks() -> f64 {
@@ -50,18 +78,178 @@ pub fn halt() {
   x86_64::instructions::hlt();
 }
 
-pub fn uptime() -> f64 {
+fn pit_uptime() -> f64 {
   time_between_ticks() * ticks() as f64
 }
 
+/// Seconds since boot, measured with the HPET when there is one.
+///
+/// Without one it counts PIT ticks, or TSC ticks once the PIT is stopped.
+pub fn uptime() -> f64 {
+  let start = HPET_START_UPTIME.load(Ordering::Acquire);
+  match hpet::nanoseconds() {
+    Some(nanoseconds) if start != 0 => return f64::from_bits(start) + nanoseconds as f64 / 1_000_000_000.0,
+    _ => {},
+  }
+
+  let stop = PIT_STOP_UPTIME.load(Ordering::Acquire);
+  if stop != 0 {
+    let elapsed = monotonic_ns().saturating_sub(PIT_STOP_NS.load(Ordering::Relaxed));
+    return f64::from_bits(stop) + elapsed as f64 / 1_000_000_000.0;
+  }
+  pit_uptime()
+}
+
+/// Switches `uptime` over to the HPET, which must have just been started.
+pub(crate) fn use_hpet() {
+  // Never store zero, that means the PIT is still in use
+  let start = pit_uptime().max(f64::MIN_POSITIVE);
+  HPET_START_UPTIME.store(start.to_bits(), Ordering::Release);
+}
+
 pub fn last_rtc_update() -> usize {
   LAST_RTC_UPDATE.load(Ordering::Relaxed)
 }
 
+/// Masks the PIT tick, once the local APIC timer wakes sleepers up.
+///
+/// Returns `false` and keeps it running when `uptime` can't do without it,
+/// which is the case without an HPET or an invariant TSC.
+pub(crate) fn stop_pit_tick() -> bool {
+  if !hpet::is_available() && !(tsc_is_invariant() && tsc_frequency().is_some()) {
+    return false;
+  }
+
+  interrupts::without_interrupts(|| {
+    // Never store zero, that means the PIT still ticks
+    let now = uptime().max(f64::MIN_POSITIVE);
+    PIT_STOP_NS.store(monotonic_ns(), Ordering::Relaxed);
+    PIT_STOP_UPTIME.store(now.to_bits(), Ordering::Release);
+    apic::set_isa_irq_masked(0, true);
+  });
+  true
+}
+
+/// Blocks for `seconds`, halting the CPU until the deadline.
 pub fn sleep(seconds: f64) {
-  let start = uptime();
-  while uptime() - start < seconds {
-    halt();
+  use x86_64::instructions::interrupts::{are_enabled, disable, enable_and_hlt};
+
+  let deadline = monotonic_ns() + (seconds * NANOSECONDS_PER_SECOND as f64) as u64;
+  let id = add_timer(deadline, None);
+  while monotonic_ns() < deadline {
+    if are_enabled() {
+      disable();
+      program_next_deadline();
+      enable_and_hlt();
+    } else {
+      core::hint::spin_loop();
+    }
+  }
+  remove_timer(id);
+}
+
+/// Returns a future that completes `nanoseconds` from now.
+pub fn sleep_for(nanoseconds: u64) -> Sleep {
+  sleep_until(monotonic_ns().saturating_add(nanoseconds))
+}
+
+/// Returns a future that completes once `monotonic_ns` reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+  Sleep { deadline, timer: None }
+}
+
+/// Future returned by `sleep_for` and `sleep_until`.
+pub struct Sleep {
+  deadline: u64,
+  timer: Option<u64>,
+}
+
+impl Future for Sleep {
+  type Output = ();
+
+  fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+    if monotonic_ns() >= self.deadline {
+      if let Some(id) = self.timer.take() {
+        remove_timer(id);
+      }
+      return Poll::Ready(());
+    }
+
+    match self.timer {
+      Some(id) => update_timer(id, context.waker()),
+      None => self.timer = Some(add_timer(self.deadline, Some(context.waker().clone()))),
+    }
+    Poll::Pending
+  }
+}
+
+impl Drop for Sleep {
+  fn drop(&mut self) {
+    if let Some(id) = self.timer.take() {
+      remove_timer(id);
+    }
+  }
+}
+
+fn add_timer(deadline: u64, waker: Option<Waker>) -> u64 {
+  let id = NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed);
+  interrupts::without_interrupts(|| {
+    TIMERS.lock().push(Timer { id, deadline, waker, fired: false });
+  });
+  id
+}
+
+fn update_timer(id: u64, waker: &Waker) {
+  interrupts::without_interrupts(|| {
+    let mut timers = TIMERS.lock();
+    if let Some(timer) = timers.iter_mut().find(|timer| timer.id == id) {
+      if !timer.waker.as_ref().map_or(false, |current| current.will_wake(waker)) {
+        timer.waker = Some(waker.clone());
+      }
+    }
+  });
+}
+
+fn remove_timer(id: u64) {
+  interrupts::without_interrupts(|| {
+    TIMERS.lock().retain(|timer| timer.id != id);
+  });
+}
+
+/// Earliest deadline a sleep is still waiting on.
+pub fn next_deadline() -> Option<u64> {
+  interrupts::without_interrupts(|| {
+    TIMERS.lock().iter().filter(|timer| !timer.fired).map(|timer| timer.deadline).min()
+  })
+}
+
+/// Arms the local APIC timer for the earliest pending sleep, or stops it when
+/// nothing sleeps. Meant to be called right before halting.
+///
+/// Without the local APIC timer the PIT tick wakes sleepers up instead.
+pub fn program_next_deadline() {
+  if !apic_timer::is_active() {
+    return;
+  }
+  match next_deadline() {
+    Some(deadline) => apic_timer::arm_oneshot(deadline),
+    None => apic_timer::stop(),
+  }
+}
+
+/// Wakes up every sleep whose deadline has passed. Called from the timer
+/// interrupts, so it must neither allocate nor free.
+pub(crate) fn expire_timers() {
+  let now = monotonic_ns();
+  let mut timers = match TIMERS.try_lock() {
+    Some(timers) => timers,
+    None => return,
+  };
+  for timer in timers.iter_mut().filter(|timer| !timer.fired && timer.deadline <= now) {
+    timer.fired = true;
+    if let Some(waker) = &timer.waker {
+      waker.wake_by_ref();
+    }
   }
 }
 
@@ -69,18 +257,150 @@ pub fn ticks() -> usize {
   PIT_TICKS.load(Ordering::Relaxed)
 }
 
+/// Busy waits for `nanosecs` nanoseconds.
 pub fn nanowait(nanosecs: u64) {
-  let start = rdtsc();
-  let delta = nanosecs * CLOCKS_PER_NANOSECOND.load(Ordering::Relaxed);
-  while rdtsc() - start < delta {
+  let deadline = monotonic_ns() + nanosecs;
+  while monotonic_ns() < deadline {
     core::hint::spin_loop();
   }
 }
 
+/// Nanoseconds since the TSC was calibrated, read straight from the TSC.
+///
+/// A TSC that isn't invariant changes its rate with power states, so the
+/// HPET is read instead when there is one. Before calibration it falls back
+/// to `uptime`, which is far coarser.
+pub fn monotonic_ns() -> u64 {
+  let frequency = TSC_FREQUENCY.load(Ordering::Acquire);
+  if frequency == 0 {
+    return (uptime() * NANOSECONDS_PER_SECOND as f64) as u64;
+  }
+  if !tsc_is_invariant() {
+    if let Some(nanoseconds) = hpet::nanoseconds() {
+      return nanoseconds.wrapping_sub(MONOTONIC_HPET_START.load(Ordering::Relaxed));
+    }
+  }
+
+  let elapsed = rdtsc().wrapping_sub(TSC_START.load(Ordering::Relaxed));
+  (elapsed as u128 * NANOSECONDS_PER_SECOND as u128 / frequency as u128) as u64
+}
+
+/// TSC value at which `monotonic_ns` reaches `nanoseconds`, once calibrated
+/// and as long as `monotonic_ns` reads the TSC.
+pub fn tsc_at(nanoseconds: u64) -> Option<u64> {
+  if !tsc_is_invariant() && hpet::is_available() {
+    return None;
+  }
+  let frequency = tsc_frequency()?;
+  let ticks = (nanoseconds as u128 * frequency as u128 / NANOSECONDS_PER_SECOND as u128) as u64;
+  Some(TSC_START.load(Ordering::Relaxed).wrapping_add(ticks))
+}
+
+/// TSC ticks per second, once calibrated.
+pub fn tsc_frequency() -> Option<u64> {
+  match TSC_FREQUENCY.load(Ordering::Acquire) {
+    0 => None,
+    frequency => Some(frequency),
+  }
+}
+
+/// Whether the TSC keeps the same rate across P-, C- and T-states.
+pub fn tsc_is_invariant() -> bool {
+  TSC_INVARIANT.load(Ordering::Relaxed)
+}
+
+fn detect_invariant_tsc() -> bool {
+  let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+  if max_extended_leaf < 0x8000_0007 {
+    return false;
+  }
+  // Advanced power management information, EDX bit 8
+  unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// Counts TSC ticks while the HPET advances `CALIBRATION_MS`.
+fn calibrate_against_hpet() -> Option<u64> {
+  let start_ns = hpet::nanoseconds()?;
+  let start_tsc = rdtsc();
+  let mut now_ns = start_ns;
+  while now_ns - start_ns < CALIBRATION_MS * 1_000_000 {
+    now_ns = hpet::nanoseconds()?;
+  }
+  let ticks = rdtsc() - start_tsc;
+  Some((ticks as u128 * NANOSECONDS_PER_SECOND as u128 / (now_ns - start_ns) as u128) as u64)
+}
+
+/// Counts TSC ticks while PIT channel 2 counts down `CALIBRATION_MS`.
+///
+/// Channel 2 only drives the speaker, so channel 0 keeps ticking meanwhile.
+/// Returns zero if the count never runs out, as with a missing PIT.
+fn calibrate_against_pit() -> u64 {
+  let latch = (PIT_INPUT_FREQUENCY * CALIBRATION_MS / 1000) as u16;
+  let mut gate: Port<u8> = Port::new(PIT_GATE_PORT);
+  let mut cmd: Port<u8> = Port::new(PIT_COMMAND_PORT);
+  let mut data: Port<u8> = Port::new(PIT_CHANNEL_2_PORT);
+
+  unsafe {
+    // Gate high, speaker off
+    let value = gate.read();
+    gate.write((value & !0x02) | 0x01);
+
+    // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+    cmd.write(0xB0);
+    data.write(latch as u8);
+    data.write((latch >> 8) as u8);
+
+    let start = rdtsc();
+    // OUT2 goes high once the count reaches zero
+    let mut polls = 0;
+    while gate.read() & 0x20 == 0 {
+      polls += 1;
+      if polls == PIT_CALIBRATION_MAX_POLLS {
+        return 0;
+      }
+      core::hint::spin_loop();
+    }
+    let ticks = rdtsc() - start;
+    ticks * 1000 / CALIBRATION_MS
+  }
+}
+
+/// Measures the TSC frequency against the HPET, or the PIT without one.
+///
+/// Must be called after `hpet::init`.
+pub fn calibrate_tsc() {
+  let invariant = detect_invariant_tsc();
+  TSC_INVARIANT.store(invariant, Ordering::Relaxed);
+
+  let (frequency, reference) = interrupts::without_interrupts(|| {
+    match calibrate_against_hpet() {
+      Some(frequency) => (frequency, "HPET"),
+      None => (calibrate_against_pit(), "PIT"),
+    }
+  });
+
+  if frequency == 0 {
+    kprintln!("[ TIME ] TSC calibration against the {} timed out, TSC not calibrated.", reference);
+    return;
+  }
+
+  MONOTONIC_HPET_START.store(hpet::nanoseconds().unwrap_or(0), Ordering::Relaxed);
+  TSC_START.store(rdtsc(), Ordering::Relaxed);
+  TSC_FREQUENCY.store(frequency, Ordering::Release);
+
+  kprintln!("[ TIME ] TSC runs at {} MHz (calibrated against the {}{}).", frequency / 1_000_000, reference,
+    if invariant { ", invariant" } else { ", not invariant" });
+}
+
 pub fn on_timer_interrupt(
   _stack_frame: InterruptStackFrame
 ) {
   PIT_TICKS.fetch_add(1, Ordering::Relaxed);
+
+  // Once the local APIC timer runs it wakes sleepers up on its own
+  if !apic_timer::is_active() {
+    expire_timers();
+  }
 }
 
 pub fn on_rtc_interrupt(
@@ -1,5 +1,5 @@
 #![no_std]
-#![feature(asm,llvm_asm,abi_x86_interrupt,alloc_error_handler)]
+#![feature(asm,global_asm,llvm_asm,abi_x86_interrupt,alloc_error_handler,allocator_api,try_reserve)]
 
 use bootloader::BootInfo;
 
@@ -11,6 +11,8 @@ extern crate alloc;
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    // The reclaimers already ran, see kernel::memory::oom
+    kernel::memory::oom::report(layout);
     panic!("allocation error: {:?}", layout)
 }
 
@@ -28,6 +30,32 @@ pub fn init(boot_info: &'static BootInfo) -> ! {
   unsafe {
     kernel::memory::init(boot_info);
   }
+  kernel::memory::stack::register_boot_stack();
+  kernel::gdt::init_interrupt_stacks();
+
+  // Read what we need from the ACPI tables before their memory is reclaimed
+  kernel::acpi::init();
+
+  // Route interrupts through the APIC when there is one
+  kernel::interrupts::apic::init();
+
+  // Prefer the HPET over the PIT as clock source
+  kernel::hpet::init();
+  kernel::time::calibrate_tsc();
+
+  // Wake sleepers up with the local APIC timer instead of the PIT tick
+  kernel::interrupts::apic_timer::init();
+
+  // Bring up the application processors
+  kernel::cpu::init();
+  kernel::smp::init();
+
+  // Our own GDT is loaded and the ACPI tables are parsed, so the memory used
+  // during boot can be handed out
+  unsafe {
+    kernel::memory::reclaim_boot_memory();
+    kernel::memory::reclaim_acpi_memory();
+  }
 
   // Initiate PCI Controllers
   kernel::pci::init();
@@ -12,7 +12,8 @@ bootloader::entry_point!(kernel_main);
 
 /// This function is called on panic.
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
+  kprintln!("[ KERNEL PANIC ] {}", info);
 
   loop {
     os_x86::kernel::time::halt();